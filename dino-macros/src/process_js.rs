@@ -80,7 +80,7 @@ pub(crate) fn process_into_js(input: DeriveInput) -> TokenStream {
     quote! {
         impl #merged rquickjs::IntoJs<'js> for #ident #generics {
             fn into_js(self, ctx: &rquickjs::Ctx<'js>) -> rquickjs::Result<rquickjs::Value<'js>> {
-                let obj = ctx.globals();
+                let obj = rquickjs::Object::new(ctx.clone())?;
                 #(#code)*
                 Ok(obj.into())
             }
@@ -90,7 +90,7 @@ pub(crate) fn process_into_js(input: DeriveInput) -> TokenStream {
     /*
     impl<'js> rquickjs::IntoJs<'js> for Request {
         fn into_js(self, ctx: &rquickjs::Ctx<'js>) -> rquickjs::Result<rquickjs::Value<'js>> {
-            let obj = ctx.globals();
+            let obj = rquickjs::Object::new(ctx.clone())?;
             obj.set("headers", self.headers)?;
             obj.set("method", self.method)?;
             obj.set("url", self.url)?;
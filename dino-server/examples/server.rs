@@ -1,7 +1,4 @@
-use dino_server::{
-    start_server, ProjectConfig, SwappableAppRouter, SwappableWorkerPool, TenentRouter,
-    TenentWorkerPool,
-};
+use dino_server::{start_server, ProjectConfig, ServerOptions, SwappableTenant};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
@@ -28,16 +25,8 @@ async fn main() -> anyhow::Result<()> {
     })();
     "#;
 
-    let routers = vec![TenentRouter::new(
-        "localhost",
-        SwappableAppRouter::try_new(code, config.routes)?,
-    )];
+    let tenants = vec![SwappableTenant::try_new("localhost", code, config, 10)?];
 
-    let pools = vec![TenentWorkerPool::new(
-        "localhost",
-        SwappableWorkerPool::try_new(code, 10)?,
-    )];
-
-    start_server(8888, routers, pools).await?;
+    start_server(8888, tenants, ServerOptions::default()).await?;
     Ok(())
 }
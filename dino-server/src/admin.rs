@@ -0,0 +1,81 @@
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::{AppError, AppState, ServerOptions, SwappableTenant, TenantStatus};
+
+#[derive(Debug, Deserialize)]
+pub struct CanaryWeight {
+    pub weight: u8,
+}
+
+/// Start the admin server on localhost, used to inspect tenants and to
+/// promote or abort canary versions.
+pub async fn start_admin_server(port: u16, tenants: Vec<SwappableTenant>) -> anyhow::Result<()> {
+    let addr = format!("127.0.0.1:{port}");
+    let listener = TcpListener::bind(addr).await?;
+
+    info!("Admin listening on {}", listener.local_addr()?);
+
+    let state = AppState::try_new(tenants, ServerOptions::default())?;
+    let app = Router::new()
+        .route("/tenants", get(list_tenants))
+        .route("/tenants/:host", get(get_tenant))
+        .route("/tenants/:host/canary/weight", post(set_canary_weight))
+        .route("/tenants/:host/promote", post(promote))
+        .route("/tenants/:host/abort", post(abort))
+        .with_state(state);
+
+    axum::serve(listener, app.into_make_service()).await?;
+
+    Ok(())
+}
+
+async fn list_tenants(State(state): State<AppState>) -> Json<Vec<TenantStatus>> {
+    let tenants = state.tenants.iter().map(|t| t.status()).collect();
+    Json(tenants)
+}
+
+async fn get_tenant(
+    State(state): State<AppState>,
+    Path(host): Path<String>,
+) -> Result<Json<TenantStatus>, AppError> {
+    let tenant = state.tenant(&host)?;
+    Ok(Json(tenant.status()))
+}
+
+async fn set_canary_weight(
+    State(state): State<AppState>,
+    Path(host): Path<String>,
+    Json(CanaryWeight { weight }): Json<CanaryWeight>,
+) -> Result<Json<TenantStatus>, AppError> {
+    let tenant = state.tenant(&host)?;
+    tenant.set_canary_weight(weight)?;
+    info!("Canary weight of {host} set to {weight}");
+    Ok(Json(tenant.status()))
+}
+
+async fn promote(
+    State(state): State<AppState>,
+    Path(host): Path<String>,
+) -> Result<Json<TenantStatus>, AppError> {
+    let tenant = state.tenant(&host)?;
+    tenant.promote()?;
+    info!("Canary of {host} promoted");
+    Ok(Json(tenant.status()))
+}
+
+async fn abort(
+    State(state): State<AppState>,
+    Path(host): Path<String>,
+) -> Result<Json<TenantStatus>, AppError> {
+    let tenant = state.tenant(&host)?;
+    tenant.abort()?;
+    info!("Canary of {host} aborted");
+    Ok(Json(tenant.status()))
+}
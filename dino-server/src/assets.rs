@@ -0,0 +1,308 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+            IF_NONE_MATCH, IF_RANGE, RANGE,
+        },
+        request::Parts,
+        HeaderValue, Method, Response, StatusCode,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+/// served when a directory, e.g. `/`, is requested
+const INDEX_FILE: &str = "index.html";
+
+/// html is revalidated on every request, so a new deploy is picked up at
+/// once. Other files are cached for a while, revalidated with the etag.
+const HTML_CACHE_CONTROL: &str = "no-cache";
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+
+/// The file listing the assets of a build, written by `dino build` next to
+/// the asset directory as `<dir>.json`. Inside it, it would clash with an
+/// asset of the same name, e.g. the `manifest.json` of a PWA.
+pub fn asset_manifest_path(dir: impl AsRef<Path>) -> PathBuf {
+    let dir = dir.as_ref();
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".json");
+    dir.with_file_name(name)
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AssetManifest {
+    /// files by their path relative to the asset directory, using `/`
+    pub files: BTreeMap<String, AssetEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssetEntry {
+    /// content hash of the file
+    pub hash: String,
+    pub size: u64,
+}
+
+/// The static files of a tenant version, kept in memory.
+#[derive(Debug, Default)]
+pub struct AssetStore {
+    files: HashMap<String, Asset>,
+}
+
+#[derive(Debug)]
+struct Asset {
+    content: Bytes,
+    content_type: HeaderValue,
+    etag: HeaderValue,
+    cache_control: HeaderValue,
+}
+
+impl AssetStore {
+    /// Load the files listed in the manifest of a built asset directory.
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let manifest = asset_manifest_path(dir);
+        let manifest: AssetManifest = serde_json::from_slice(
+            &fs::read(&manifest)
+                .with_context(|| format!("failed to read {}", manifest.display()))?,
+        )?;
+
+        let mut files = HashMap::with_capacity(manifest.files.len());
+        for (name, entry) in manifest.files {
+            let content = fs::read(dir.join(&name))
+                .with_context(|| format!("failed to read asset {name}"))?;
+            let mime = mime_guess::from_path(&name).first_or_octet_stream();
+            let cache_control = if mime.subtype() == mime_guess::mime::HTML {
+                HTML_CACHE_CONTROL
+            } else {
+                ASSET_CACHE_CONTROL
+            };
+            let asset = Asset {
+                content: content.into(),
+                content_type: HeaderValue::from_str(mime.as_ref())?,
+                etag: HeaderValue::from_str(&format!("\"{}\"", entry.hash))?,
+                cache_control: HeaderValue::from_static(cache_control),
+            };
+            files.insert(name, asset);
+        }
+        Ok(Self { files })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Serve the asset at `path`, `None` if it is not an asset and the
+    /// request should go to the routes.
+    pub fn serve(&self, parts: &Parts, path: &str) -> Option<Response<Body>> {
+        if parts.method != Method::GET && parts.method != Method::HEAD {
+            return None;
+        }
+        let asset = self.get(path)?;
+        let headers = &parts.headers;
+
+        let mut res = Response::builder()
+            .header(CONTENT_TYPE, asset.content_type.clone())
+            .header(ETAG, asset.etag.clone())
+            .header(CACHE_CONTROL, asset.cache_control.clone())
+            .header(ACCEPT_RANGES, "bytes");
+
+        if let Some(v) = headers.get(IF_NONE_MATCH) {
+            if etag_matches(v, &asset.etag) {
+                return Some(
+                    res.status(StatusCode::NOT_MODIFIED)
+                        .body(Body::empty())
+                        .unwrap(),
+                );
+            }
+        }
+
+        let len = asset.content.len();
+        // a range of an older version of the file is useless to the client
+        let if_range = headers.get(IF_RANGE).is_none_or(|v| *v == asset.etag);
+        let range = match headers.get(RANGE) {
+            Some(v) if if_range => parse_range(v.to_str().unwrap_or_default(), len),
+            _ => RangeResult::Full,
+        };
+        let (status, content) = match range {
+            RangeResult::Full => (StatusCode::OK, asset.content.clone()),
+            RangeResult::Partial(range) => {
+                let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+                res = res.header(CONTENT_RANGE, content_range);
+                (StatusCode::PARTIAL_CONTENT, asset.content.slice(range))
+            }
+            RangeResult::Unsatisfiable => {
+                return Some(
+                    res.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(CONTENT_RANGE, format!("bytes */{len}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                );
+            }
+        };
+
+        res = res.status(status).header(CONTENT_LENGTH, content.len());
+        let body = match parts.method {
+            Method::HEAD => Body::empty(),
+            _ => Body::from(content),
+        };
+        Some(res.body(body).unwrap())
+    }
+
+    fn get(&self, path: &str) -> Option<&Asset> {
+        let name = path.trim_start_matches('/');
+        if name.is_empty() || name.ends_with('/') {
+            return self.files.get(&format!("{name}{INDEX_FILE}"));
+        }
+        self.files.get(name)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeResult {
+    Full,
+    Partial(Range<usize>),
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header. Only a single byte range is supported, anything
+/// else is answered with the full content.
+fn parse_range(value: &str, len: usize) -> RangeResult {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeResult::Full;
+    };
+    if spec.contains(',') {
+        return RangeResult::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeResult::Full;
+    };
+
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // bytes=-500, the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RangeResult::Unsatisfiable;
+            }
+            len.saturating_sub(suffix)..len
+        }
+        // bytes=500-
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(len),
+        _ => return RangeResult::Full,
+    };
+    if range.start >= len {
+        return RangeResult::Unsatisfiable;
+    }
+    RangeResult::Partial(range)
+}
+
+fn etag_matches(value: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    value
+        .split(',')
+        .map(|v| v.trim().trim_start_matches("W/"))
+        .any(|v| v == "*" || etag == v)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+
+    fn parts(method: Method, headers: &[(&str, &str)]) -> Parts {
+        let mut req = Request::builder().method(method).uri("/");
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn parse_range_should_work() {
+        assert_eq!(parse_range("bytes=0-9", 100), RangeResult::Partial(0..10));
+        assert_eq!(parse_range("bytes=90-", 100), RangeResult::Partial(90..100));
+        assert_eq!(parse_range("bytes=-10", 100), RangeResult::Partial(90..100));
+        assert_eq!(
+            parse_range("bytes=50-200", 100),
+            RangeResult::Partial(50..100)
+        );
+        assert_eq!(parse_range("bytes=100-", 100), RangeResult::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), RangeResult::Full);
+        assert_eq!(parse_range("items=0-1", 100), RangeResult::Full);
+    }
+
+    #[test]
+    fn asset_store_should_serve_files() -> anyhow::Result<()> {
+        let store = AssetStore::load("fixtures/assets")?;
+        assert_eq!(store.len(), 3);
+        assert!(store
+            .serve(&parts(Method::GET, &[]), "/api/hello")
+            .is_none());
+        assert!(store.serve(&parts(Method::POST, &[]), "/").is_none());
+
+        let res = store.serve(&parts(Method::GET, &[]), "/").unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html");
+        assert_eq!(res.headers()[CACHE_CONTROL], HTML_CACHE_CONTROL);
+        let etag = res.headers()[ETAG].to_str()?.to_string();
+
+        let res = store
+            .serve(
+                &parts(Method::GET, &[("if-none-match", &etag)]),
+                "/index.html",
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = store
+            .serve(&parts(Method::GET, &[("range", "bytes=0-3")]), "/js/app.js")
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/javascript");
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes 0-3/21");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "4");
+
+        let res = store
+            .serve(
+                &parts(Method::GET, &[("range", "bytes=100-")]),
+                "/js/app.js",
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes */21");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn asset_store_should_serve_a_user_manifest() -> anyhow::Result<()> {
+        assert_eq!(
+            asset_manifest_path(".build/0123-assets/"),
+            Path::new(".build/0123-assets.json")
+        );
+        // the manifest of the build is not in the asset directory, so the
+        // project's `manifest.json` is served as is
+        let store = AssetStore::load("fixtures/assets")?;
+        let res = store
+            .serve(&parts(Method::GET, &[]), "/manifest.json")
+            .unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, fs::read("fixtures/assets/manifest.json")?);
+        Ok(())
+    }
+}
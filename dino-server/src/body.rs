@@ -0,0 +1,200 @@
+use std::fmt;
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap,
+    },
+};
+use serde::Serialize;
+
+use crate::{AppError, RouteEntry};
+
+/// max bytes of a request body, unless the tenant or route sets one
+pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// An error of an invalid request body, at the JSON pointer `pointer`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BodyError {
+    pub pointer: String,
+    pub message: String,
+}
+
+/// Read the body of a request handled by `route` and check it against the
+/// size, content types and schema of the route. `limit` is the max size of
+/// the tenant, used if the route has none.
+pub(crate) async fn read_body(
+    headers: &HeaderMap,
+    body: Body,
+    route: &RouteEntry,
+    limit: usize,
+) -> Result<Bytes, AppError> {
+    let limit = route.max_body_size.unwrap_or(limit);
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    // no need to read a body which is too large anyway
+    if length.is_some_and(|len| len > limit as u64) {
+        return Err(AppError::PayloadTooLarge(limit));
+    }
+    // the body may be longer than its `Content-Length`, or have none
+    let body = to_bytes(body, limit)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge(limit))?;
+    if body.is_empty() && route.body_schema.is_none() {
+        return Ok(body);
+    }
+
+    if !route.content_types.is_empty() {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !content_type_allowed(content_type, &route.content_types) {
+            let content_type = if content_type.is_empty() {
+                "none"
+            } else {
+                content_type
+            };
+            return Err(AppError::UnsupportedMediaType(content_type.to_string()));
+        }
+    }
+
+    if let Some(schema) = &route.body_schema {
+        let value: serde_json::Value = serde_json::from_slice(&body).map_err(|e| {
+            AppError::InvalidBody(vec![BodyError {
+                pointer: String::new(),
+                message: format!("invalid JSON: {e}"),
+            }])
+        })?;
+        let result = schema.validate(&value);
+        if let Err(errors) = result {
+            let errors = errors
+                .map(|e| BodyError {
+                    pointer: e.instance_path.to_string(),
+                    message: e.to_string(),
+                })
+                .collect();
+            return Err(AppError::InvalidBody(errors));
+        }
+    }
+    Ok(body)
+}
+
+/// Whether the media type of `content_type` is one of `allowed`, e.g.
+/// `application/json; charset=utf-8` is allowed by `application/json` and
+/// `application/*`.
+fn content_type_allowed(content_type: &str, allowed: &[String]) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let Some((ty, _)) = essence.split_once('/') else {
+        return false;
+    };
+    allowed.iter().any(|a| {
+        let a = a.to_ascii_lowercase();
+        match a.strip_suffix("/*") {
+            Some(prefix) => prefix == ty,
+            None => a == essence,
+        }
+    })
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.pointer.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.pointer, self.message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderName, HeaderValue, Method, StatusCode};
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::{AppRouter, ProjectConfig};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v)))
+            .collect()
+    }
+
+    #[test]
+    fn content_type_allowed_should_work() {
+        let allowed = ["application/json".to_string(), "text/*".to_string()];
+        assert!(content_type_allowed("application/json", &allowed));
+        assert!(content_type_allowed(
+            "Application/JSON; charset=utf-8",
+            &allowed
+        ));
+        assert!(content_type_allowed("text/plain", &allowed));
+        assert!(!content_type_allowed("application/xml", &allowed));
+        assert!(!content_type_allowed("text", &allowed));
+        assert!(!content_type_allowed("", &allowed));
+    }
+
+    #[tokio::test]
+    async fn read_body_should_check_size_type_and_schema() -> anyhow::Result<()> {
+        let config = ProjectConfig::load("fixtures/config8.yml")?;
+        let app_router = AppRouter::try_new(&config)?;
+        let m = app_router.match_it(Method::PUT, "/api/admin/users/1")?;
+        // the size is inherited from the group
+        assert_eq!(m.value.max_body_size, Some(64));
+        let json = headers(&[("content-type", "application/json")]);
+        let read = |headers: HeaderMap, body: &'static str| {
+            let route = m.value;
+            async move { read_body(&headers, Body::from(body), route, 1024).await }
+        };
+
+        let body = read(json.clone(), r#"{"name": "alice", "age": 7}"#).await?;
+        assert_eq!(body.len(), 27);
+
+        let large = r#"{"name": "alice", "bio": "a long story about alice in wonderland"}"#;
+        let err = read(json.clone(), large).await.unwrap_err();
+        assert!(matches!(err, AppError::PayloadTooLarge(64)));
+        let length = headers(&[
+            ("content-type", "application/json"),
+            ("content-length", "65"),
+        ]);
+        let err = read(length, "{}").await.unwrap_err();
+        assert!(matches!(err, AppError::PayloadTooLarge(64)));
+
+        let err = read(headers(&[("content-type", "text/plain")]), "alice")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::UnsupportedMediaType(ref t) if t == "text/plain"));
+        let err = read(HeaderMap::new(), "alice").await.unwrap_err();
+        assert!(matches!(err, AppError::UnsupportedMediaType(ref t) if t == "none"));
+
+        let err = read(json.clone(), r#"{"age": "7"}"#).await.unwrap_err();
+        let AppError::InvalidBody(errors) = &err else {
+            panic!("expect invalid body, got {err}");
+        };
+        let mut pointers: Vec<_> = errors.iter().map(|e| e.pointer.as_str()).collect();
+        pointers.sort();
+        assert_eq!(pointers, ["", "/age"]);
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+        let err = read(json, "{").await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidBody(ref e) if e[0].pointer.is_empty()));
+
+        // no checks without config, up to the limit of the tenant
+        let m = app_router.match_it(Method::GET, "/api/hello")?;
+        let body = read_body(&HeaderMap::new(), Body::from("hi"), m.value, 1024).await?;
+        assert_eq!(body, "hi");
+        let err = read_body(&HeaderMap::new(), Body::from("hi"), m.value, 1).await;
+        assert!(matches!(err, Err(AppError::PayloadTooLarge(1))));
+        Ok(())
+    }
+}
@@ -1,35 +1,920 @@
-use std::path::Path;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
 
-use crate::ProjectRoutes;
+use crate::{bundle_exports, config_error::suggest, ConfigError, ProjectRoutes, INIT_HANDLER};
+use anyhow::{bail, Context};
 use axum::http::Method;
-use serde::{Deserialize, Deserializer};
+use indexmap::IndexMap;
+use schemars::JsonSchema;
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer,
+};
+use serde_yaml::{Mapping, Value};
 
-#[derive(Debug, Deserialize)]
+/// max depth of nested `include`s, to stop include cycles
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// version of the config schema supported by this dino-server
+pub const CONFIG_VERSION: u32 = 1;
+
+const METHODS: [&str; 9] = [
+    "GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS", "CONNECT", "TRACE",
+];
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ProjectConfig {
+    /// version of the config schema
+    #[serde(default = "default_version")]
+    pub version: u32,
     pub name: String,
+    /// files merged into this one, relative to it. `ProjectConfig::load`
+    /// resolves them, so this is empty after loading.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// hosts served by the project: `example.com`, `*.example.com` for any
+    /// subdomain, or `*` to serve all hosts no other project serves
+    #[serde(default = "default_hosts")]
+    pub hosts: Vec<String>,
+    /// port `dino run` listens on when `--port` is not given, a number or a
+    /// string like `${PORT}`
+    #[serde(default, deserialize_with = "deserialize_port")]
+    #[schemars(with = "Option<u16>")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub isolation: Isolation,
+    /// values passed to the `init` hook of the bundle
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+    /// the default pool, used by routes without a `pool`
+    #[serde(default)]
+    pub pool: PoolConfig,
+    /// dedicated pools which routes can refer to by name
+    #[serde(default)]
+    pub pools: IndexMap<String, PoolConfig>,
+    /// directory of static files, served before the routes are matched.
+    /// `dino build` replaces it with the built asset directory.
+    pub assets: Option<String>,
+    /// paths answered with a redirect, checked before anything else
+    #[serde(default)]
+    pub redirects: Vec<RedirectConfig>,
+    /// paths served by another asset or route, e.g. `/v1/*rest` to
+    /// `/api/*rest`
+    #[serde(default)]
+    pub rewrites: Vec<RewriteConfig>,
+    /// cors policy of the routes without their own, and of the assets
+    pub cors: Option<CorsConfig>,
+    /// limit of the requests a client can make to the tenant, on top of the
+    /// limits of the routes
+    pub rate_limit: Option<RateLimitConfig>,
+    /// yaml file with the api keys and basic auth users of `auth`, relative
+    /// to the project directory. Keep it out of version control.
+    pub secrets: Option<String>,
+    /// JSON Web Key Set file with the keys verifying the JWTs of `auth`
+    pub jwks: Option<String>,
+    /// max bytes of a request body, 2 MiB by default
+    pub max_body_size: Option<usize>,
+    /// max responses kept by the response cache of the routes with `cache`
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+    /// handler for requests which match no route
+    pub fallback: Option<String>,
+    /// handler for requests which match a route, but none of its methods
+    pub method_not_allowed: Option<String>,
+    pub routes: ProjectRoutes,
+}
+
+/// How js state is shared between the requests served by one worker.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// all requests of a worker reuse one context
+    #[default]
+    Shared,
+    /// every request runs in a fresh context, so no global state leaks
+    /// between requests. The bundle is compiled once per worker, then
+    /// evaluated and `init` called in the context of every request. This
+    /// costs about 20x a shared context, see `benches/isolation.rs`
+    PerRequest,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// number of workers, defaults to the pool size of the server
+    pub size: Option<usize>,
+    /// max time (in milliseconds) to wait for the response of a worker
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub recycle: RecyclePolicy,
+}
+
+/// When a worker should be replaced by a fresh one. A worker is recycled
+/// as soon as any of the limits is reached.
+#[derive(Debug, Default, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RecyclePolicy {
+    /// max requests served by one worker
+    pub max_requests: Option<u64>,
+    /// max memory (in bytes) used by the worker's js runtime
+    pub max_memory: Option<usize>,
+    /// max age (in seconds) of one worker
+    pub max_age: Option<u64>,
+}
+
+/// Result of checking the handlers named in the config against the
+/// functions exported by the bundle.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HandlerCheck {
+    /// handlers used by the config but not exported by the bundle
+    pub missing: Vec<String>,
+    /// functions exported by the bundle but used by no route
+    pub unused: Vec<String>,
+}
+
+/// Which cross-origin requests browsers may make. Preflight requests are
+/// answered by the server, without calling js.
+#[derive(Debug, Default, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// allowed origins like `https://example.com`, or `*` for any
+    pub origins: Vec<String>,
+    /// allowed methods, defaults to the methods of the route
+    #[serde(default, deserialize_with = "deserialize_method_list")]
+    #[schemars(with = "Vec<String>")]
+    pub methods: Vec<Method>,
+    /// allowed request headers, or `*` for any
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// allow cookies and `Authorization`, not possible with origin `*`
+    #[serde(default)]
+    pub credentials: bool,
+    /// how long (in seconds) browsers may cache a preflight response
+    pub max_age: Option<u64>,
+}
+
+/// A token bucket per client: `requests` every `period` seconds, in bursts
+/// of up to `burst` requests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+    /// in seconds
+    #[serde(default = "default_rate_limit_period")]
+    pub period: u64,
+    /// defaults to `requests`
+    pub burst: Option<u32>,
+    /// how clients are told apart: `ip`, `header:<name>`, or `subject` for
+    /// the authenticated user. Requests without the key fall back to `ip`.
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub key: RateLimitKey,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    Header(String),
+    Subject,
+}
+
+/// How requests to a route are authenticated. A request accepted by any of
+/// the schemes reaches the handler, with its identity as `req.auth`. Others
+/// get 401 without calling js.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `Authorization: Bearer <jwt>`, verified with the keys of `jwks`
+    pub jwt: Option<JwtConfig>,
+    /// a key of the secrets file in a header
+    pub api_key: Option<ApiKeyConfig>,
+    /// `Authorization: Basic`, with the users of the secrets file
+    pub basic: Option<BasicAuthConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    #[serde(default = "default_jwt_algorithms")]
+    pub algorithms: Vec<JwtAlgorithm>,
+    /// required `iss` claim
+    pub issuer: Option<String>,
+    /// required `aud` claim
+    pub audience: Option<String>,
+    /// seconds of clock skew allowed when checking `exp` and `nbf`
+    #[serde(default)]
+    pub leeway: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    #[serde(default = "default_api_key_header")]
+    pub header: String,
+    /// names of the accepted keys in the secrets file, all if empty
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// accepted users of the secrets file, all if empty
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Responses are cached by method, path, query, the `vary` request headers
+/// and the authenticated user. `Cache-Control` of the handler wins over
+/// `ttl`, `no-store`, `no-cache` and `private` responses are not cached.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// seconds a response is served from the cache
+    pub ttl: u64,
+    /// request headers which select different responses
+    #[serde(default)]
+    pub vary: Vec<String>,
+}
+
+/// Redirect `from` to `to`. Params of `from`, e.g. `:id` or `*rest`, are
+/// substituted in `to`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub from: String,
+    pub to: String,
+    /// one of 301, 302, 303, 307 and 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+/// Serve `from` as if `to` was requested, without a round trip to the
+/// client.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub from: String,
+    pub to: String,
+}
+
+/// An entry of `routes`: the routes of one path, or a group of routes
+/// sharing a path prefix and settings.
+#[derive(Debug, JsonSchema)]
+#[schemars(untagged)]
+pub enum RouteNode {
+    Routes(Vec<ProjectRoute>),
+    Group(Box<RouteGroup>),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RouteGroup {
+    /// default pool of the routes in the group
+    pub pool: Option<String>,
+    /// default timeout (in milliseconds) of the routes in the group
+    pub timeout: Option<u64>,
+    /// run before the middleware of the routes in the group
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    /// default cors policy of the routes in the group
+    pub cors: Option<CorsConfig>,
+    /// default rate limit of each route in the group
+    pub rate_limit: Option<RateLimitConfig>,
+    /// default authentication of the routes in the group
+    pub auth: Option<AuthConfig>,
+    /// default response caching of the routes in the group
+    pub cache: Option<CacheConfig>,
+    /// default max bytes of the request bodies of the routes in the group
+    pub max_body_size: Option<usize>,
+    /// default accepted content types of the routes in the group
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// default JSON Schema of the request bodies of the routes in the group
+    pub body_schema: Option<String>,
     pub routes: ProjectRoutes,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ProjectRoute {
-    #[serde(deserialize_with = "deserialize_method")]
-    pub method: Method,
+    /// `GET`, a list like `[GET, POST]`, or `ANY`
+    #[serde(deserialize_with = "deserialize_methods")]
+    #[schemars(with = "OneOrMany")]
+    pub method: RouteMethods,
     pub handler: String,
+    /// name of the pool running the handler, defaults to the tenant's pool
+    pub pool: Option<String>,
+    /// max time (in milliseconds) to wait for the handler, overrides the
+    /// timeout of the pool
+    pub timeout: Option<u64>,
+    /// exported js functions run in order before the handler, after the
+    /// middleware of the groups
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    /// replaces the cors policy of the group or the tenant
+    pub cors: Option<CorsConfig>,
+    /// replaces the rate limit of the group
+    pub rate_limit: Option<RateLimitConfig>,
+    /// replaces the authentication of the group
+    pub auth: Option<AuthConfig>,
+    /// cache the responses of `GET` and `HEAD` requests in memory
+    pub cache: Option<CacheConfig>,
+    /// max bytes of the request body, overrides the `max_body_size` of the
+    /// tenant
+    pub max_body_size: Option<usize>,
+    /// accepted `Content-Type`s of a non-empty body, e.g. `application/json`
+    /// or `text/*`, any if empty
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// JSON Schema file the request body must be valid against, relative to
+    /// the project directory. Invalid bodies get 400 without calling js.
+    pub body_schema: Option<String>,
+}
+
+/// Methods handled by a route: `GET`, `[GET, POST]` or `ANY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteMethods {
+    Any,
+    Methods(Vec<Method>),
 }
 
 impl ProjectConfig {
+    /// Load and check a config file. Errors point to the file, line and
+    /// column of the invalid value.
     pub fn load(filename: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let content = std::fs::read_to_string(filename)?;
-        let config: ProjectConfig = serde_yaml::from_str(&content)?;
+        Self::load_env(filename, None)
+    }
+
+    /// Load a config file with the overlay of `env` merged on top of it,
+    /// e.g. `config.prod.yml` for `config.yml`. `${VAR}` and
+    /// `${VAR:-default}` in string values are replaced with environment
+    /// variables.
+    pub fn load_env(filename: impl AsRef<Path>, env: Option<&str>) -> anyhow::Result<Self> {
+        let mut files = vec![];
+        let mut value = load_yaml_with_env(filename.as_ref(), env, &mut files)?;
+        interpolate(&mut value, &|name| std::env::var(name).ok())?;
+        let config: ProjectConfig =
+            serde_path_to_error::deserialize(value).map_err(|e| ConfigError::new(&files, e))?;
+        if config.version != CONFIG_VERSION {
+            bail!(
+                "{}: unsupported config version {}, expected {CONFIG_VERSION}",
+                filename.as_ref().display(),
+                config.version
+            );
+        }
         Ok(config)
     }
+
+    /// Load the config file as yaml, with the files listed in `include`
+    /// merged in order and the overlay of `env` on top. Included paths are
+    /// relative to the including file.
+    pub fn load_yaml(filename: impl AsRef<Path>, env: Option<&str>) -> anyhow::Result<Value> {
+        load_yaml_with_env(filename.as_ref(), env, &mut vec![])
+    }
+
+    /// The yaml `load_env` deserializes: `load_yaml` with environment
+    /// variables interpolated.
+    pub fn resolve_yaml(filename: impl AsRef<Path>, env: Option<&str>) -> anyhow::Result<Value> {
+        let mut value = Self::load_yaml(filename, env)?;
+        interpolate(&mut value, &|name| std::env::var(name).ok())?;
+        Ok(value)
+    }
+
+    /// Path of the overlay of `env`, `config.<env>.yml` for `config.yml`.
+    pub fn overlay_path(filename: impl AsRef<Path>, env: &str) -> PathBuf {
+        let filename = filename.as_ref();
+        let stem = filename.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match filename.extension() {
+            Some(ext) => format!("{stem}.{env}.{}", ext.to_string_lossy()),
+            None => format!("{stem}.{env}"),
+        };
+        filename.with_file_name(name)
+    }
+
+    /// JSON Schema of the config file, e.g. for editor completion.
+    pub fn json_schema() -> serde_json::Value {
+        let schema = schemars::schema_for!(ProjectConfig);
+        serde_json::to_value(schema).expect("schema should be valid json")
+    }
+
+    /// All routes by their full path, with the settings inherited from
+    /// their groups.
+    pub fn flat_routes(&self) -> anyhow::Result<IndexMap<String, Vec<ProjectRoute>>> {
+        let mut ret = IndexMap::new();
+        flatten_routes("", &self.routes, &RouteGroup::default(), &mut ret)?;
+        Ok(ret)
+    }
+
+    /// Names of all handlers and middleware the config refers to.
+    pub fn handlers(&self) -> anyhow::Result<BTreeSet<String>> {
+        let mut names: BTreeSet<String> = self
+            .flat_routes()?
+            .into_values()
+            .flatten()
+            .flat_map(|route| route.middleware.into_iter().chain([route.handler]))
+            .collect();
+        names.extend(self.fallback.iter().cloned());
+        names.extend(self.method_not_allowed.iter().cloned());
+        Ok(names)
+    }
+
+    /// Check that every handler of the config is a function exported by
+    /// `code`, the bundled js module.
+    pub fn check_handlers(&self, code: &str) -> anyhow::Result<HandlerCheck> {
+        let exports = bundle_exports(code)?;
+        let handlers = self.handlers()?;
+        Ok(HandlerCheck {
+            missing: handlers.difference(&exports).cloned().collect(),
+            unused: exports
+                .difference(&handlers)
+                .filter(|name| *name != INIT_HANDLER)
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+impl Default for RouteGroup {
+    fn default() -> Self {
+        Self {
+            pool: None,
+            timeout: None,
+            middleware: vec![],
+            cors: None,
+            rate_limit: None,
+            auth: None,
+            cache: None,
+            max_body_size: None,
+            content_types: vec![],
+            body_schema: None,
+            routes: ProjectRoutes::new(),
+        }
+    }
 }
 
-fn deserialize_method<'de, D>(deserializer: D) -> Result<Method, D::Error>
+impl TryFrom<String> for RateLimitKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "ip" => Ok(RateLimitKey::Ip),
+            "subject" => Ok(RateLimitKey::Subject),
+            _ => match s.strip_prefix("header:").map(str::trim) {
+                Some(name) if !name.is_empty() => Ok(RateLimitKey::Header(name.to_string())),
+                _ => Err(format!(
+                    "invalid rate limit key `{s}`, expected `ip`, `header:<name>` or `subject`"
+                )),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RouteNode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RouteNodeVisitor;
+
+        impl<'de> Visitor<'de> for RouteNodeVisitor {
+            type Value = RouteNode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of routes or a route group")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<RouteNode, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(RouteNode::Routes)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<RouteNode, A::Error> {
+                RouteGroup::deserialize(MapAccessDeserializer::new(map))
+                    .map(|group| RouteNode::Group(Box::new(group)))
+            }
+        }
+
+        deserializer.deserialize_any(RouteNodeVisitor)
+    }
+}
+
+impl RecyclePolicy {
+    /// Returns the reason to recycle a worker, if any limit is reached.
+    pub fn exceeded(&self, served: u64, memory: usize, age: Duration) -> Option<String> {
+        if let Some(max) = self.max_requests.filter(|max| served >= *max) {
+            return Some(format!("served {served} requests (max {max})"));
+        }
+        if let Some(max) = self.max_memory.filter(|max| memory >= *max) {
+            return Some(format!("used {memory} bytes (max {max})"));
+        }
+        if let Some(max) = self.max_age.filter(|max| age.as_secs() >= *max) {
+            return Some(format!("alive for {}s (max {max}s)", age.as_secs()));
+        }
+        None
+    }
+}
+
+fn default_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_hosts() -> Vec<String> {
+    vec!["localhost".to_string()]
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_rate_limit_period() -> u64 {
+    1
+}
+
+fn default_cache_size() -> usize {
+    1024
+}
+
+fn default_jwt_algorithms() -> Vec<JwtAlgorithm> {
+    vec![JwtAlgorithm::RS256, JwtAlgorithm::EdDSA]
+}
+
+fn default_api_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+fn default_realm() -> String {
+    "dino".to_string()
+}
+
+fn flatten_routes(
+    prefix: &str,
+    routes: &ProjectRoutes,
+    group: &RouteGroup,
+    ret: &mut IndexMap<String, Vec<ProjectRoute>>,
+) -> anyhow::Result<()> {
+    for (path, node) in routes {
+        let path = match path.as_str() {
+            // `/` in a group is the group's own path
+            "/" if !prefix.is_empty() => prefix.to_string(),
+            _ => format!("{}{}", prefix.trim_end_matches('/'), path),
+        };
+        match node {
+            RouteNode::Routes(routes) => {
+                let routes = routes
+                    .iter()
+                    .map(|route| ProjectRoute {
+                        pool: route.pool.clone().or_else(|| group.pool.clone()),
+                        timeout: route.timeout.or(group.timeout),
+                        middleware: [&group.middleware[..], &route.middleware[..]].concat(),
+                        cors: route.cors.clone().or_else(|| group.cors.clone()),
+                        rate_limit: route
+                            .rate_limit
+                            .clone()
+                            .or_else(|| group.rate_limit.clone()),
+                        auth: route.auth.clone().or_else(|| group.auth.clone()),
+                        cache: route.cache.clone().or_else(|| group.cache.clone()),
+                        max_body_size: route.max_body_size.or(group.max_body_size),
+                        content_types: inherit_list(&route.content_types, &group.content_types),
+                        body_schema: route
+                            .body_schema
+                            .clone()
+                            .or_else(|| group.body_schema.clone()),
+                        ..route.clone()
+                    })
+                    .collect();
+                if ret.insert(path.clone(), routes).is_some() {
+                    bail!("duplicate route path: {path}");
+                }
+            }
+            RouteNode::Group(sub) => {
+                let defaults = RouteGroup {
+                    pool: sub.pool.clone().or_else(|| group.pool.clone()),
+                    timeout: sub.timeout.or(group.timeout),
+                    middleware: [&group.middleware[..], &sub.middleware[..]].concat(),
+                    cors: sub.cors.clone().or_else(|| group.cors.clone()),
+                    rate_limit: sub.rate_limit.clone().or_else(|| group.rate_limit.clone()),
+                    auth: sub.auth.clone().or_else(|| group.auth.clone()),
+                    cache: sub.cache.clone().or_else(|| group.cache.clone()),
+                    max_body_size: sub.max_body_size.or(group.max_body_size),
+                    content_types: inherit_list(&sub.content_types, &group.content_types),
+                    body_schema: sub
+                        .body_schema
+                        .clone()
+                        .or_else(|| group.body_schema.clone()),
+                    routes: ProjectRoutes::new(),
+                };
+                flatten_routes(&path, &sub.routes, &defaults, ret)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `own` if set, otherwise the list inherited from the group.
+fn inherit_list(own: &[String], group: &[String]) -> Vec<String> {
+    if own.is_empty() { group } else { own }.to_vec()
+}
+
+fn load_yaml_with_env(
+    filename: &Path,
+    env: Option<&str>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> anyhow::Result<Value> {
+    let mut base_files = vec![];
+    let mut value = load_yaml_with_includes(filename, 0, &mut base_files)?;
+    if let Some(env) = env {
+        if env.is_empty()
+            || !env
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("invalid environment name: {env}");
+        }
+        let path = ProjectConfig::overlay_path(filename, env);
+        anyhow::ensure!(
+            path.exists(),
+            "no config for environment {env}: {} not found",
+            path.display()
+        );
+        let overlay = load_yaml_with_includes(&path, 0, files)?;
+        merge_overlay(&mut value, overlay);
+    }
+    // values of the overlay win, so errors are looked up there first
+    files.extend(base_files);
+    Ok(value)
+}
+
+/// `files` collects the path and content of every file read, to locate
+/// errors in them later.
+fn load_yaml_with_includes(
+    filename: &Path,
+    depth: usize,
+    files: &mut Vec<(PathBuf, String)>,
+) -> anyhow::Result<Value> {
+    if depth > MAX_INCLUDE_DEPTH {
+        bail!("too many nested includes: {}", filename.display());
+    }
+    let content = std::fs::read_to_string(filename)
+        .with_context(|| format!("failed to read {}", filename.display()))?;
+    let mut value: Value = serde_yaml::from_str(&content)
+        .with_context(|| format!("failed to parse {}", filename.display()))?;
+    files.push((filename.to_path_buf(), content));
+
+    let includes = match value.as_mapping_mut() {
+        Some(map) => map.remove("include"),
+        None => None,
+    };
+    let includes: Vec<PathBuf> = match includes {
+        Some(includes) => serde_yaml::from_value(includes)
+            .with_context(|| format!("invalid include in {}", filename.display()))?,
+        None => vec![],
+    };
+
+    let dir = filename.parent().unwrap_or(Path::new("."));
+    for include in includes {
+        let path = include_path(dir, &include)?;
+        let included = load_yaml_with_includes(&path, depth + 1, files)?;
+        merge_included(&mut value, included, "")
+            .with_context(|| format!("failed to include {}", path.display()))?;
+    }
+    Ok(value)
+}
+
+/// Path of an `include` of a file in `dir`. Includes must stay within the
+/// project, absolute paths and `..` could read any file of the host.
+fn include_path(dir: &Path, include: &Path) -> anyhow::Result<PathBuf> {
+    let within = include
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !within {
+        bail!(
+            "invalid include {}: must be a relative path without `..`",
+            include.display()
+        );
+    }
+    Ok(dir.join(include))
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in the string values of `value`
+/// with the result of `lookup`, `$$` is a literal `$`. The results stay
+/// strings, numeric fields like `port` parse them. Every undefined variable
+/// is reported at once.
+fn interpolate(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+    let mut undefined = vec![];
+    interpolate_value(value, lookup, "", &mut undefined)?;
+    if !undefined.is_empty() {
+        bail!("undefined environment variables: {}", undefined.join(", "));
+    }
+    Ok(())
+}
+
+fn interpolate_value(
+    value: &mut Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    key: &str,
+    undefined: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    match value {
+        Value::String(s) => {
+            if !s.contains('$') {
+                return Ok(());
+            }
+            *s = interpolate_str(s, lookup, undefined)
+                .with_context(|| format!("invalid interpolation at {key}"))?;
+        }
+        Value::Sequence(seq) => {
+            for (i, v) in seq.iter_mut().enumerate() {
+                interpolate_value(v, lookup, &format!("{key}[{i}]"), undefined)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (k, v) in map.iter_mut() {
+                let k = k.as_str().unwrap_or_default();
+                let key = if key.is_empty() {
+                    k.to_string()
+                } else {
+                    format!("{key}.{k}")
+                };
+                interpolate_value(v, lookup, &key, undefined)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Undefined variables are added to `undefined` and left out of the result.
+fn interpolate_str(
+    s: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    undefined: &mut Vec<String>,
+) -> anyhow::Result<String> {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        ret.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            ret.push('$');
+            rest = r;
+            continue;
+        }
+        let Some(r) = rest.strip_prefix('{') else {
+            ret.push('$');
+            continue;
+        };
+        let Some((expr, r)) = r.split_once('}') else {
+            bail!("unclosed `${{` in `{s}`");
+        };
+        rest = r;
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!("invalid variable name `{name}`");
+        }
+        match lookup(name).or_else(|| default.map(String::from)) {
+            Some(v) => ret.push_str(&v),
+            None if !undefined.iter().any(|u| u == name) => undefined.push(name.to_string()),
+            None => {}
+        }
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+/// Merge an environment overlay into the config. Mappings are merged key by
+/// key, any other value of the overlay replaces the base one.
+fn merge_overlay(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(existing) => merge_overlay(existing, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Merge an included file into the config. Mappings are merged key by key,
+/// any other value defined in both is reported as a duplicate.
+fn merge_included(base: &mut Value, other: Value, key: &str) -> anyhow::Result<()> {
+    match (base, other) {
+        (Value::Mapping(base), Value::Mapping(other)) => merge_mapping(base, other, key),
+        (base, other) if base.is_null() => {
+            // e.g. an include-only config file
+            *base = other;
+            Ok(())
+        }
+        _ => bail!("duplicate key: {key}"),
+    }
+}
+
+fn merge_mapping(base: &mut Mapping, other: Mapping, key: &str) -> anyhow::Result<()> {
+    for (k, v) in other {
+        let name = match &k {
+            Value::String(s) if key.is_empty() => s.clone(),
+            Value::String(s) => format!("{key}.{s}"),
+            _ => format!("{key}.{k:?}"),
+        };
+        match base.get_mut(&k) {
+            Some(existing) => merge_included(existing, v, &name)?,
+            None => {
+                base.insert(k, v);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_methods<'de, D>(deserializer: D) -> Result<RouteMethods, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    };
+    let mut methods = Vec::with_capacity(names.len());
+    for name in names {
+        if name.eq_ignore_ascii_case("ANY") {
+            return Ok(RouteMethods::Any);
+        }
+        methods.push(parse_method(&name).map_err(serde::de::Error::custom)?);
+    }
+    if methods.is_empty() {
+        return Err(serde::de::Error::custom("no method given"));
+    }
+    Ok(RouteMethods::Methods(methods))
+}
+
+fn deserialize_port<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        String(String),
+    }
+    match Option::<Port>::deserialize(deserializer)? {
+        Some(Port::Number(port)) => Ok(Some(port)),
+        Some(Port::String(s)) => s
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid port `{s}`"))),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_method_list<'de, D>(deserializer: D) -> Result<Vec<Method>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(deserializer)?;
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| parse_method(name).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn parse_method(s: &str) -> Result<Method, String> {
     match s.to_uppercase().as_str() {
         "GET" => Ok(Method::GET),
         "POST" => Ok(Method::POST),
@@ -40,6 +925,240 @@ where
         "OPTIONS" => Ok(Method::OPTIONS),
         "CONNECT" => Ok(Method::CONNECT),
         "TRACE" => Ok(Method::TRACE),
-        _ => Err(serde::de::Error::custom("invalid method")),
+        _ => match suggest(s, METHODS) {
+            Some(method) => Err(format!("invalid method `{s}`, did you mean `{method}`?")),
+            None => Err(format!(
+                "invalid method `{s}`, expected one of {}",
+                METHODS.join(", ")
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_groups_and_includes_should_work() -> anyhow::Result<()> {
+        let config = ProjectConfig::load("fixtures/config5.yml")?;
+        let routes = config.flat_routes()?;
+        assert_eq!(
+            routes.keys().collect::<Vec<_>>(),
+            [
+                "/api/health",
+                "/api/admin/reports",
+                "/api/admin/jobs/:id",
+                "/api/users",
+                "/api/users/:id",
+            ]
+        );
+
+        let route = &routes["/api/admin/reports"][0];
+        assert_eq!(route.pool.as_deref(), Some("heavy"));
+        assert_eq!(route.timeout, Some(5000));
+        assert_eq!(route.middleware, ["auth"]);
+        let route = &routes["/api/admin/jobs/:id"][0];
+        assert_eq!(route.pool.as_deref(), Some("default"));
+        assert_eq!(route.timeout, Some(60000));
+        assert_eq!(route.middleware, ["auth", "audit"]);
+        let route = &routes["/api/users/:id"][0];
+        assert_eq!(route.pool, None);
+        assert_eq!(route.timeout, Some(100));
+        Ok(())
+    }
+
+    #[test]
+    fn config_check_handlers_should_work() -> anyhow::Result<()> {
+        let config = ProjectConfig::load("fixtures/config3.yml")?;
+        let code = r#"
+(function(){
+    async function hello(req){ return { status: 200, headers: {} }; }
+    async function spa(req){ return { status: 200, headers: {} }; }
+    async function legacy(req){ return { status: 200, headers: {} }; }
+    function init(env){}
+    return { hello, spa, legacy, init };
+})();
+        "#;
+        let check = config.check_handlers(code)?;
+        assert_eq!(
+            check,
+            HandlerCheck {
+                missing: vec!["method_not_allowed".into(), "not_found".into()],
+                unused: vec!["legacy".into()],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn config_errors_should_have_locations() {
+        let err = ProjectConfig::load("fixtures/config_invalid.yml").unwrap_err();
+        let err = err.downcast::<ConfigError>().unwrap();
+        assert_eq!(err.location, Some((6, 7)));
+        assert_eq!(err.path, "routes./api/users[0]");
+        assert_eq!(err.suggestion.as_deref(), Some("handler"));
+        let msg = err.to_string();
+        assert!(msg.starts_with(
+            "fixtures/config_invalid.yml:6:7: routes./api/users[0]: unknown field `handlr`"
+        ));
+        assert!(msg.ends_with("did you mean `handler`?"));
+
+        let err = ProjectConfig::load("fixtures/config_invalid2.yml").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fixtures/config_invalid2.yml:7:7: routes./api/users[0].method: \
+             invalid method `GTE`, did you mean `GET`?"
+        );
+    }
+
+    #[test]
+    fn config_json_schema_should_work() {
+        let schema = ProjectConfig::json_schema();
+        assert_eq!(schema["additionalProperties"], false);
+        assert!(schema["properties"]["routes"].is_object());
+        assert_eq!(schema["required"], serde_json::json!(["name", "routes"]));
+    }
+
+    #[test]
+    fn config_env_overlay_should_work() -> anyhow::Result<()> {
+        assert_eq!(
+            ProjectConfig::overlay_path("fixtures/config2.yml", "prod"),
+            Path::new("fixtures/config2.prod.yml")
+        );
+
+        let config = ProjectConfig::load_env("fixtures/config2.yml", Some("prod"))?;
+        assert_eq!(config.pool.timeout, Some(500));
+        assert_eq!(config.pools["heavy"].size, Some(4));
+        assert_eq!(config.pools["heavy"].timeout, Some(30000));
+        assert_eq!(config.env["mode"], "prod");
+        let routes = config.flat_routes()?;
+        assert_eq!(routes["/api/report"][0].handler, "report_v2");
+        assert_eq!(routes["/api/health"][0].handler, "hello");
+
+        let err = ProjectConfig::load_env("fixtures/config2.yml", Some("staging")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no config for environment staging: fixtures/config2.staging.yml not found"
+        );
+        assert!(ProjectConfig::load_env("fixtures/config2.yml", Some("../x")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn interpolate_should_work() -> anyhow::Result<()> {
+        let lookup = |name: &str| match name {
+            "API_URL" => Some("https://api.example.com".to_string()),
+            "PORT" => Some("8080".to_string()),
+            _ => None,
+        };
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+port: ${PORT:-3000}
+env:
+  upstream: ${API_URL}/v1
+  price: $$5
+  mode: ${MODE:-dev}
+hosts: ["${HOST:-localhost}"]
+"#,
+        )?;
+        interpolate(&mut value, &lookup)?;
+        assert_eq!(value["port"], "8080");
+        assert_eq!(value["env"]["upstream"], "https://api.example.com/v1");
+        assert_eq!(value["env"]["price"], "$5");
+        assert_eq!(value["env"]["mode"], "dev");
+        assert_eq!(value["hosts"][0], "localhost");
+
+        let mut value: Value =
+            serde_yaml::from_str("name: ${NAME}\nenv:\n  a: ${A}-${NAME}\n  b: ${B:-}")?;
+        let err = interpolate(&mut value, &lookup).unwrap_err();
+        assert_eq!(err.to_string(), "undefined environment variables: NAME, A");
+        assert_eq!(value["env"]["b"], "");
+
+        let mut value: Value = serde_yaml::from_str("name: ${NAME")?;
+        assert!(interpolate(&mut value, &lookup).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn interpolated_values_should_stay_strings() -> anyhow::Result<()> {
+        let lookup = |name: &str| match name {
+            "API_KEY" => Some("12345".to_string()),
+            "DEBUG" => Some("true".to_string()),
+            "PORT" => Some("8080".to_string()),
+            _ => None,
+        };
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+name: ${NAME:-dino}
+port: ${PORT}
+env:
+  API_KEY: ${API_KEY}
+  DEBUG: "${DEBUG}"
+routes: {}
+"#,
+        )?;
+        interpolate(&mut value, &lookup)?;
+        let config: ProjectConfig = serde_yaml::from_value(value)?;
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(config.env["API_KEY"], "12345");
+        assert_eq!(config.env["DEBUG"], "true");
+
+        let config: ProjectConfig = serde_yaml::from_str(
+            "name: a
+port: 3000
+routes: {}",
+        )?;
+        assert_eq!(config.port, Some(3000));
+        let err = serde_yaml::from_str::<ProjectConfig>(
+            "name: a
+port: abc
+routes: {}",
+        );
+        assert!(err.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_duplicate_paths_should_fail() {
+        let config: ProjectConfig = serde_yaml::from_str(
+            r#"
+name: dup
+routes:
+  /api/users:
+    - method: GET
+      handler: users
+  /api:
+    routes:
+      /users:
+        - method: POST
+          handler: create_user
+"#,
+        )
+        .unwrap();
+        let err = config.flat_routes().unwrap_err();
+        assert_eq!(err.to_string(), "duplicate route path: /api/users");
+    }
+
+    #[test]
+    fn merge_included_should_reject_conflicts() {
+        let mut base: Value = serde_yaml::from_str("name: a\nroutes:\n  /a: []").unwrap();
+        let other: Value = serde_yaml::from_str("routes:\n  /b: []").unwrap();
+        merge_included(&mut base, other, "").unwrap();
+        assert_eq!(base["routes"].as_mapping().unwrap().len(), 2);
+
+        let other: Value = serde_yaml::from_str("routes:\n  /a: []").unwrap();
+        let err = merge_included(&mut base, other, "").unwrap_err();
+        assert_eq!(err.to_string(), "duplicate key: routes./a");
+    }
+
+    #[test]
+    fn include_path_should_stay_within_project() {
+        let dir = Path::new("project");
+        let path = include_path(dir, Path::new("./routes/users.yml")).unwrap();
+        assert_eq!(path, Path::new("project/./routes/users.yml"));
+        assert!(include_path(dir, Path::new("../secrets.yml")).is_err());
+        assert!(include_path(dir, Path::new("routes/../../secrets.yml")).is_err());
+        assert!(include_path(dir, Path::new("/etc/app.yml")).is_err());
     }
 }
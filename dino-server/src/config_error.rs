@@ -0,0 +1,304 @@
+use std::{fmt, path::PathBuf};
+
+use serde_path_to_error::Segment;
+
+/// An invalid value in a config file, with its location if it could be
+/// found.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    /// 1-based line and column
+    pub location: Option<(usize, usize)>,
+    /// path of the value in the config, e.g. `routes./api[0].handler`
+    pub path: String,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+/// A step of the path to a value in a yaml document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathKey {
+    Key(String),
+    Index(usize),
+}
+
+impl ConfigError {
+    /// Locate a deserialize error in `files`, the config file and the files
+    /// it includes, in that order.
+    pub(crate) fn new(
+        files: &[(PathBuf, String)],
+        err: serde_path_to_error::Error<serde_yaml::Error>,
+    ) -> Self {
+        let message = err.inner().to_string();
+        let mut keys: Vec<PathKey> = err
+            .path()
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Seq { index } => Some(PathKey::Index(*index)),
+                Segment::Map { key } => Some(PathKey::Key(key.clone())),
+                _ => None,
+            })
+            .collect();
+        // an unknown field is reported at the map containing it, and located
+        // at the field itself. The path may or may not end with the field
+        let mut path = err.path().to_string();
+        if let Some(field) = message
+            .strip_prefix("unknown field `")
+            .and_then(|s| s.split('`').next())
+        {
+            let field = PathKey::Key(field.to_string());
+            if keys.last() == Some(&field) {
+                let len = path.rfind('.').unwrap_or(0);
+                path.truncate(len);
+                if path.is_empty() {
+                    path.push('.');
+                }
+            } else {
+                keys.push(field);
+            }
+        }
+
+        let suggestion = suggest_from_message(&message);
+        // the longest prefix of the path found in any of the files
+        let (file, location) = (0..=keys.len())
+            .rev()
+            .find_map(|len| {
+                files.iter().find_map(|(file, source)| {
+                    locate(source, &keys[..len]).map(|loc| (file.clone(), Some(loc)))
+                })
+            })
+            .unwrap_or_else(|| {
+                let file = files.first().map(|(f, _)| f.clone()).unwrap_or_default();
+                (file, None)
+            });
+
+        Self {
+            file,
+            location,
+            path,
+            message,
+            suggestion,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{line}:{column}")?;
+        }
+        if self.path != "." {
+            write!(f, ": {}", self.path)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean `{suggestion}`?")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The closest of `candidates` to `word`, if it is close enough to be a
+/// typo.
+pub(crate) fn suggest<'a>(
+    word: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let word = word.to_ascii_lowercase();
+    let max = (word.len() / 3).max(2);
+    candidates
+        .into_iter()
+        .map(|c| (levenshtein(&word, &c.to_ascii_lowercase()), c))
+        .filter(|(d, _)| *d <= max)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// Suggestion for serde's "unknown field `x`, expected one of `a`, `b`"
+/// and "unknown variant" errors.
+fn suggest_from_message(message: &str) -> Option<String> {
+    let rest = message
+        .strip_prefix("unknown field `")
+        .or_else(|| message.strip_prefix("unknown variant `"))?;
+    let (word, rest) = rest.split_once('`')?;
+    let expected = rest
+        .strip_prefix(", expected one of ")
+        .or_else(|| rest.strip_prefix(", expected "))?;
+    let candidates = expected
+        .split(", ")
+        .flat_map(|c| c.split(" or "))
+        .map(|c| c.trim_matches('`'));
+    suggest(word, candidates).map(String::from)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Find the 1-based line and column of the value at `path` in a yaml
+/// document. Only block style is followed, as written by hand.
+fn locate(source: &str, path: &[PathKey]) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = source.lines().collect();
+    // the current node: its first line, the column its content starts at
+    // and the line after its last one
+    let mut line = (0..lines.len()).find(|&l| content(&lines, l, 0, 0).is_some())?;
+    let mut col = indent(lines[line]);
+    let mut end = lines.len();
+
+    for key in path {
+        match key {
+            PathKey::Key(key) => {
+                let (l, c) = (line..end).find_map(|l| {
+                    let c = content(&lines, l, line, col)?;
+                    let text = &lines[l][c..];
+                    (c == col && is_key(text, key)).then_some((l, c))
+                })?;
+                end = block_end(&lines, l, c, end);
+                line = l;
+                col = c;
+                // the value is on the following lines, unless it is inline
+                if let Some((l, c)) =
+                    (l + 1..end).find_map(|l| Some((l, content(&lines, l, l, 0)?)))
+                {
+                    line = l;
+                    col = c;
+                }
+            }
+            PathKey::Index(index) => {
+                let items: Vec<usize> = (line..end)
+                    .filter(|&l| {
+                        content(&lines, l, line, col) == Some(col)
+                            && lines[l][col..].starts_with('-')
+                    })
+                    .collect();
+                let l = *items.get(*index)?;
+                end = items.get(index + 1).copied().unwrap_or(end);
+                // the content of the item starts after `- `
+                let rest = &lines[l][col + 1..];
+                line = l;
+                col = col + 1 + (rest.len() - rest.trim_start().len());
+            }
+        }
+    }
+    Some((line + 1, col + 1))
+}
+
+/// Column of the content of line `l`, `None` for blank and comment lines.
+/// The node starting at `start` begins at `start_col` of its first line.
+fn content(lines: &[&str], l: usize, start: usize, start_col: usize) -> Option<usize> {
+    let c = if l == start {
+        start_col
+    } else {
+        indent(lines[l])
+    };
+    let text = lines[l].get(c..)?.trim_start();
+    if text.is_empty() || text.starts_with('#') {
+        return None;
+    }
+    Some(lines[l].len() - text.len())
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn is_key(text: &str, key: &str) -> bool {
+    [key.to_string(), format!("\"{key}\""), format!("'{key}'")]
+        .iter()
+        .filter_map(|k| text.strip_prefix(k.as_str())?.strip_prefix(':'))
+        .any(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+/// The line after the block of the key at `l`, which ends at the next line
+/// indented at most as much, except for a sequence at the key's indent.
+fn block_end(lines: &[&str], l: usize, col: usize, end: usize) -> usize {
+    (l + 1..end)
+        .find(|&i| {
+            content(lines, i, i, 0)
+                .is_some_and(|c| c < col || (c == col && !lines[i][c..].starts_with("- ")))
+        })
+        .unwrap_or(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"
+name: dino-test
+routes:
+  /api/users:
+    # list users
+    - method: GET
+      handler: users
+    - method: POST
+      handlr: create_user
+  /api/groups:
+    pool: heavy
+    routes:
+      /:id:
+      - method: GET
+        handler: group
+"#;
+
+    fn path(keys: &[&str]) -> Vec<PathKey> {
+        keys.iter()
+            .map(|k| match k.parse() {
+                Ok(i) => PathKey::Index(i),
+                Err(_) => PathKey::Key(k.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn locate_should_find_values() {
+        assert_eq!(locate(SOURCE, &path(&["name"])), Some((2, 1)));
+        assert_eq!(
+            locate(SOURCE, &path(&["routes", "/api/users", "1"])),
+            Some((8, 7))
+        );
+        assert_eq!(
+            locate(SOURCE, &path(&["routes", "/api/users", "1", "handlr"])),
+            Some((9, 7))
+        );
+        assert_eq!(
+            locate(
+                SOURCE,
+                &path(&["routes", "/api/groups", "routes", "/:id", "0", "handler"])
+            ),
+            Some((15, 9))
+        );
+        assert_eq!(locate(SOURCE, &path(&["routes", "/api/none"])), None);
+    }
+
+    #[test]
+    fn suggest_should_find_typos() {
+        let fields = ["method", "handler", "pool", "timeout", "middleware"];
+        assert_eq!(suggest("handlr", fields), Some("handler"));
+        assert_eq!(suggest("TIMEOUT", fields), Some("timeout"));
+        assert_eq!(suggest("websocket", fields), None);
+        assert_eq!(
+            suggest_from_message(
+                "unknown variant `per_reqest`, expected `shared` or `per_request`"
+            ),
+            Some("per_request".to_string())
+        );
+        assert_eq!(
+            suggest_from_message("unknown field `handlr`, expected one of `method`, `handler`"),
+            Some("handler".to_string())
+        );
+    }
+}
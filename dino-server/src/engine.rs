@@ -1,24 +1,88 @@
-use std::{collections::HashMap, sync::atomic::AtomicUsize, thread};
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap},
+    ffi::CString,
+    rc::Rc,
+    slice,
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 
+use anyhow::anyhow;
 use axum::{body::Body, response::Response};
 use dino_macros::{FromJs, IntoJs};
-use rquickjs::{Context, Function, Object, Promise, Runtime};
+use rquickjs::{
+    qjs, CatchResultExt, Context, Ctx, Function, IntoJs, Object, Promise, Runtime, Value,
+};
+use serde::Serialize;
 use tokio::sync::mpsc;
-use tracing::info;
+use tracing::{info, warn};
 use typed_builder::TypedBuilder;
 
-type WorkRequest = (String, Req);
-type WorkResponse = oneshot::Sender<Res>;
+use crate::{Isolation, PoolMetrics, PoolStats, RecyclePolicy};
+
+/// optional export of the bundle, called once when a worker starts. With
+/// `Isolation::PerRequest` it is also called in the fresh context of every
+/// request, so the handlers see the state it sets up.
+pub const INIT_HANDLER: &str = "init";
+
+/// file name of the bundle in js stack traces
+const BUNDLE_NAME: &str = "bundle.js";
+
+/// global running the middleware and the handler of a request
+const CHAIN_FN: &str = "__dino_chain";
+
+/// Run the middleware in order, then the handler. A middleware can return a
+/// response to skip the rest of the chain, `{ req, ctx }` to replace the
+/// request or context, or nothing.
+const CHAIN_JS: &str = r#"
+(async function(handlers, middleware, name, req) {
+    let ctx = {};
+    for (const m of middleware) {
+        const ret = await handlers[m](req, ctx);
+        if (ret === undefined || ret === null) continue;
+        if (typeof ret.status === "number") return ret;
+        if (ret.req !== undefined) req = ret.req;
+        if (ret.ctx !== undefined) ctx = ret.ctx;
+    }
+    return await handlers[name](req, ctx);
+})
+"#;
+
+/// handler name, middleware names, the request and when to interrupt it
+type WorkRequest = (String, Vec<String>, Req, Option<Instant>);
+/// the response, or the exception thrown by the js code
+type WorkResponse = oneshot::Sender<anyhow::Result<Res>>;
 
 pub struct JsWorkerPool {
     senders: Vec<mpsc::Sender<(WorkRequest, WorkResponse)>>,
     indexes: AtomicUsize,
+    metrics: Arc<PoolMetrics>,
 }
 
-#[allow(unused)]
 pub struct JsWorker {
     rt: Runtime,
     ctx: Context,
+    /// the bundle compiled once, evaluated in every new context
+    bytecode: Vec<u8>,
+    isolation: Isolation,
+    /// passed to `init` in every new context
+    env: HashMap<String, String>,
+    /// the js code of the running request is interrupted after it
+    deadline: Rc<Cell<Option<Instant>>>,
+    init_time: Option<Duration>,
+    created_at: Instant,
+    served: Cell<u64>,
+}
+
+/// Options shared by all workers of a pool.
+#[derive(Debug, Default, Clone)]
+pub struct WorkerOptions {
+    pub recycle: RecyclePolicy,
+    pub isolation: Isolation,
+    /// passed to the `init` hook of the bundle
+    pub env: HashMap<String, String>,
 }
 
 #[derive(Debug, TypedBuilder, IntoJs)]
@@ -27,14 +91,42 @@ pub struct Req {
     pub method: String,
     #[builder(setter(into))]
     pub url: String,
+    /// path the tenant is mounted at, e.g. `/t/acme`, empty if it is
+    /// served at the root. Links generated by handlers start with it.
+    #[builder(default, setter(into))]
+    pub base: String,
     #[builder(default)]
     pub query: HashMap<String, String>,
     #[builder(default)]
-    pub params: HashMap<String, String>,
+    pub params: HashMap<String, Param>,
     #[builder(default)]
     pub headers: HashMap<String, String>,
     #[builder(default)]
     pub body: Option<String>,
+    /// set for routes with `auth`, who sent the request
+    #[builder(default)]
+    pub auth: Option<Identity>,
+}
+
+/// The identity of an authenticated request, set as a request extension
+/// by the auth layer and passed to js as `req.auth`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Identity {
+    /// `jwt`, `api_key` or `basic`
+    pub scheme: &'static str,
+    /// `sub` of the jwt, name of the api key or the user
+    pub subject: String,
+    /// verified claims of the jwt, empty for the other schemes
+    pub claims: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A route param, a number if the route declares `:id<int>` or
+/// `:id<float>`, otherwise a string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    Str(String),
+    Int(i64),
+    Float(f64),
 }
 
 #[derive(Debug, FromJs)]
@@ -58,32 +150,122 @@ impl From<Res> for Response {
     }
 }
 
+/// Names of the functions exported by the bundle. The module is evaluated
+/// in a throwaway runtime, `init` is not called.
+pub fn bundle_exports(module: &str) -> anyhow::Result<BTreeSet<String>> {
+    let rt = Runtime::new()?;
+    let ctx = Context::full(&rt)?;
+    ctx.with(|ctx| {
+        let ret: Object = ctx.eval(module)?;
+        let mut names = BTreeSet::new();
+        for prop in ret.props::<String, Value>() {
+            let (name, value) = prop?;
+            if value.is_function() {
+                names.insert(name);
+            }
+        }
+        Ok(names)
+    })
+}
+
+impl<'js> IntoJs<'js> for Param {
+    fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        match self {
+            Param::Str(s) => s.into_js(ctx),
+            // js numbers are doubles, large ints lose precision like in
+            // `JSON.parse`
+            Param::Int(i) => Ok(Value::new_number(ctx.clone(), i as f64)),
+            Param::Float(f) => Ok(Value::new_float(ctx.clone(), f)),
+        }
+    }
+}
+
+impl<'js> IntoJs<'js> for Identity {
+    fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        let json = serde_json::to_string(&self).expect("identity should serialize");
+        ctx.json_parse(json)
+    }
+}
+
 fn print(msg: String) {
     println!("hi, here is rust, this is your msg: {msg}")
 }
 
 impl JsWorkerPool {
-    pub fn new(size: usize, module: &str) -> Self {
+    /// Start `size` workers and wait until all of them are initialized. If
+    /// any worker fails to start, e.g. its `init` hook throws, the pool is
+    /// not created.
+    pub fn try_new(size: usize, module: &str, options: WorkerOptions) -> anyhow::Result<Self> {
         let mut senders = Vec::with_capacity(size);
-        for _ in 0..size {
-            let (tx, mut rx) = mpsc::channel::<((String, Req), oneshot::Sender<Res>)>(1);
+        let mut readies = Vec::with_capacity(size);
+        let metrics = Arc::new(PoolMetrics::new(size));
+        for index in 0..size {
+            let (tx, mut rx) = mpsc::channel::<(WorkRequest, WorkResponse)>(1);
+            let (ready_tx, ready_rx) = oneshot::channel::<anyhow::Result<()>>();
             let code = module.to_string();
+            let options = options.clone();
+            let metrics = metrics.clone();
             thread::spawn(move || {
-                let worker = JsWorker::try_new(&code).unwrap();
-                while let Some(((name, req), res_tx)) = rx.blocking_recv() {
-                    let res = worker.run(&name, req).unwrap();
+                let mut worker = match JsWorker::with_options(&code, &options) {
+                    Ok(worker) => worker,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                metrics.initialized(index, worker.init_time());
+                let _ = ready_tx.send(Ok(()));
+
+                while let Some(((name, middleware, req, deadline), res_tx)) = rx.blocking_recv() {
+                    metrics.started(index);
+                    let start = Instant::now();
+                    // an exception fails the request, the worker keeps serving
+                    let res = worker.run_until(deadline, &middleware, &name, req);
+                    if let Err(e) = &res {
+                        warn!("[worker-{index}] {name} failed: {e}");
+                    }
+                    metrics.finished(index, &name, start.elapsed());
                     let _ = res_tx.send(res);
+                    // the channel is kept, so requests queued meanwhile are
+                    // served by the new worker
+                    if let Some(reason) = worker.should_recycle(&options.recycle) {
+                        match JsWorker::with_options(&code, &options) {
+                            Ok(new) => {
+                                info!("[worker-{index}] recycled: {reason}");
+                                metrics.initialized(index, new.init_time());
+                                worker = new;
+                            }
+                            Err(e) => warn!("[worker-{index}] failed to recycle: {e}"),
+                        }
+                    }
                 }
             });
             senders.push(tx);
+            readies.push(ready_rx);
+        }
+
+        for (index, ready) in readies.into_iter().enumerate() {
+            ready
+                .recv()?
+                .map_err(|e| e.context(format!("failed to start worker-{index}")))?;
         }
-        Self {
+
+        Ok(Self {
             senders,
             indexes: AtomicUsize::new(0),
-        }
+            metrics,
+        })
     }
 
-    pub async fn run(&self, name: &str, req: Req) -> oneshot::Receiver<Res> {
+    /// Queue a request, its js code is interrupted if it still runs at
+    /// `deadline`.
+    pub async fn run(
+        &self,
+        name: &str,
+        middleware: &[String],
+        req: Req,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<oneshot::Receiver<anyhow::Result<Res>>> {
         let index = self
             .indexes
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -93,49 +275,270 @@ impl JsWorkerPool {
 
         let sender = &self.senders[index];
         let (res_tx, res_rx) = oneshot::channel();
+        self.metrics.queued(index);
         sender
-            .send(((name.to_string(), req), res_tx))
+            .send((
+                (name.to_string(), middleware.to_vec(), req, deadline),
+                res_tx,
+            ))
             .await
-            .unwrap();
-        res_rx
+            .map_err(|_| anyhow!("worker-{index} is not running"))?;
+        Ok(res_rx)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.metrics.stats()
     }
 }
 
 impl JsWorker {
     pub fn try_new(module: &str) -> anyhow::Result<Self> {
+        Self::with_options(module, &WorkerOptions::default())
+    }
+
+    pub fn with_options(module: &str, options: &WorkerOptions) -> anyhow::Result<Self> {
         let rt = Runtime::new()?;
+        // a timeout can't stop the thread of the worker, the js code has to
+        // give up itself
+        let deadline = Rc::new(Cell::new(None::<Instant>));
+        let interrupt = deadline.clone();
+        rt.set_interrupt_handler(Some(Box::new(move || {
+            interrupt.get().is_some_and(|d| Instant::now() >= d)
+        })));
         let ctx = Context::full(&rt)?;
+        let bytecode = ctx.with(|ctx| compile(&ctx, module))?;
+        // the shared context is created in both modes, so a broken module
+        // or a failing `init` is reported here and not on the first request
+        Self::load(&ctx, &bytecode)?;
+        let init_time = Self::init(&ctx, &options.env)?;
+        if let Some(elapsed) = init_time {
+            info!("worker initialized in {elapsed:?}");
+        }
 
+        Ok(Self {
+            rt,
+            ctx,
+            bytecode,
+            isolation: options.isolation,
+            env: options.env.clone(),
+            deadline,
+            init_time,
+            created_at: Instant::now(),
+            served: Cell::new(0),
+        })
+    }
+
+    pub fn run(&self, name: &str, req: Req) -> anyhow::Result<Res> {
+        self.run_chain(&[], name, req)
+    }
+
+    /// Run the middleware in order, then the handler, as `fn(req, ctx)`.
+    pub fn run_chain(&self, middleware: &[String], name: &str, req: Req) -> anyhow::Result<Res> {
+        self.run_until(None, middleware, name, req)
+    }
+
+    /// Like `run_chain`, but the js code is interrupted with an error once
+    /// `deadline` has passed.
+    pub fn run_until(
+        &self,
+        deadline: Option<Instant>,
+        middleware: &[String],
+        name: &str,
+        req: Req,
+    ) -> anyhow::Result<Res> {
+        self.deadline.set(deadline);
+        let res = self.run_isolated(middleware, name, req);
+        self.deadline.set(None);
+        res
+    }
+
+    fn run_isolated(&self, middleware: &[String], name: &str, req: Req) -> anyhow::Result<Res> {
+        self.served.set(self.served.get() + 1);
+        match self.isolation {
+            Isolation::Shared => Self::call(&self.ctx, middleware, name, req),
+            Isolation::PerRequest => {
+                // no parsing, the bundle is evaluated from its bytecode and
+                // initialized like the shared context
+                let ctx = Context::full(&self.rt)?;
+                Self::load(&ctx, &self.bytecode)?;
+                Self::init(&ctx, &self.env)?;
+                Self::call(&ctx, middleware, name, req)
+            }
+        }
+    }
+
+    /// Time spent in the `init` hook, `None` if the bundle doesn't export one.
+    pub fn init_time(&self) -> Option<Duration> {
+        self.init_time
+    }
+
+    /// Evaluate the bundle in `ctx` and set up the globals the requests
+    /// are run with.
+    fn load(ctx: &Context, bytecode: &[u8]) -> anyhow::Result<()> {
         ctx.with(|ctx| {
             let global = ctx.globals();
-            let ret: Object = ctx.eval(module)?;
+            let ret: Object = caught(&ctx, eval_bytecode(&ctx, bytecode).and_then(|v| v.get()))?;
             global.set("handlers", ret)?;
             // setup print function
             let fun = Function::new(ctx.clone(), print)?.with_name("print")?;
             global.set("print", fun)?;
+            let chain: Function = ctx.eval(CHAIN_JS)?;
+            global.set(CHAIN_FN, chain)?;
+            Ok(())
+        })
+    }
 
-            Ok::<_, anyhow::Error>(())
-        })?;
-
-        Ok(Self { rt, ctx })
+    /// Call the `init` export of the bundle loaded in `ctx`, if any, and
+    /// return the time it took.
+    fn init(ctx: &Context, env: &HashMap<String, String>) -> anyhow::Result<Option<Duration>> {
+        ctx.with(|ctx| {
+            let handlers: Object = ctx.globals().get("handlers")?;
+            let Some(init) = handlers.get::<_, Option<Function>>(INIT_HANDLER)? else {
+                return Ok(None);
+            };
+            let start = Instant::now();
+            let v: Value = caught(&ctx, init.call((env.clone(),)))?;
+            if let Some(promise) = v.into_promise() {
+                caught(&ctx, promise.finish::<Value>())?;
+            }
+            Ok(Some(start.elapsed()))
+        })
     }
 
-    pub fn run(&self, name: &str, req: Req) -> anyhow::Result<Res> {
-        self.ctx.with(|ctx| {
+    fn call(ctx: &Context, middleware: &[String], name: &str, req: Req) -> anyhow::Result<Res> {
+        ctx.with(|ctx| {
             let globals = ctx.globals();
             let handlers = globals.get::<_, Object>("handlers")?;
-            let fun = handlers.get::<_, Function>(name)?;
-            let v: Promise = fun.call((req,))?;
-
-            Ok::<_, anyhow::Error>(v.finish()?)
+            let chain = globals.get::<_, Function>(CHAIN_FN)?;
+            let v: Promise = caught(&ctx, chain.call((handlers, middleware.to_vec(), name, req)))?;
+            caught(&ctx, v.finish())
         })
     }
+
+    /// Bytes currently used by the js runtime of this worker.
+    pub fn memory_used(&self) -> usize {
+        self.rt.memory_usage().memory_used_size as usize
+    }
+
+    pub fn should_recycle(&self, policy: &RecyclePolicy) -> Option<String> {
+        // computing memory usage walks the whole js heap, skip it if unused
+        let memory = match policy.max_memory {
+            Some(_) => self.memory_used(),
+            None => 0,
+        };
+        policy.exceeded(self.served.get(), memory, self.created_at.elapsed())
+    }
+}
+
+/// Compile the bundle, a script whose value is the object of its exports,
+/// to bytecode.
+fn compile(ctx: &Ctx, source: &str) -> anyhow::Result<Vec<u8>> {
+    let raw = ctx.as_raw().as_ptr();
+    let len = source.len();
+    let source = CString::new(source)?;
+    let name = CString::new(BUNDLE_NAME)?;
+    let flags = qjs::JS_EVAL_TYPE_GLOBAL | qjs::JS_EVAL_FLAG_COMPILE_ONLY;
+    // SAFETY: `raw` is the live context of `ctx`, the compiled function and
+    // the written buffer are freed before returning
+    unsafe {
+        let func = qjs::JS_Eval(raw, source.as_ptr(), len as _, name.as_ptr(), flags as _);
+        if qjs::JS_IsException(func) {
+            return caught(ctx, Err(rquickjs::Error::Exception));
+        }
+        let mut size = 0;
+        let buf = qjs::JS_WriteObject(raw, &mut size, func, qjs::JS_WRITE_OBJ_BYTECODE as _);
+        qjs::JS_FreeValue(raw, func);
+        if buf.is_null() {
+            return caught(ctx, Err(rquickjs::Error::Exception));
+        }
+        let bytecode = slice::from_raw_parts(buf, size as _).to_vec();
+        qjs::js_free(raw, buf as _);
+        Ok(bytecode)
+    }
+}
+
+/// Evaluate bytecode written by `compile`, the value is the one of the
+/// script.
+fn eval_bytecode<'js>(ctx: &Ctx<'js>, bytecode: &[u8]) -> rquickjs::Result<Value<'js>> {
+    let raw = ctx.as_raw().as_ptr();
+    // SAFETY: the bytecode was written by `compile` with the same quickjs,
+    // `JS_EvalFunction` takes ownership of the function read
+    unsafe {
+        let flags = qjs::JS_READ_OBJ_BYTECODE as _;
+        let func = qjs::JS_ReadObject(raw, bytecode.as_ptr(), bytecode.len() as _, flags);
+        if qjs::JS_IsException(func) {
+            return Err(rquickjs::Error::Exception);
+        }
+        let ret = qjs::JS_EvalFunction(raw, func);
+        if qjs::JS_IsException(ret) {
+            return Err(rquickjs::Error::Exception);
+        }
+        Ok(Value::from_raw(ctx.clone(), ret))
+    }
+}
+
+/// The error of a js call, with the message and stack of the exception if
+/// one was thrown.
+fn caught<T>(ctx: &Ctx, res: rquickjs::Result<T>) -> anyhow::Result<T> {
+    res.catch(ctx).map_err(|e| anyhow!("{e}"))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn bundle_exports_should_list_functions() {
+        let code = r#"
+(function(){
+    async function hello(req){ return { status: 200, headers: {} }; }
+    function init(env){}
+    return { hello, init, version: "1.0" };
+})();
+        "#;
+        let names = bundle_exports(code).unwrap();
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), ["hello", "init"]);
+    }
+
+    #[test]
+    fn js_worker_should_run_middleware() {
+        let code = r#"
+(function(){
+    async function auth(req, ctx){
+        if (!req.headers["authorization"]) {
+            return { status: 401, headers: {}, body: "unauthorized" };
+        }
+        return { ctx: { user: "alice" } };
+    }
+    function audit(req, ctx){
+        req.headers["x-audit"] = "1";
+        return { req };
+    }
+    async function hello(req, ctx){
+        return { status: 200, headers: {}, body: `${ctx.user}:${req.headers["x-audit"]}` };
+    }
+    return { auth, audit, hello };
+})();
+        "#;
+        let worker = JsWorker::try_new(code).unwrap();
+        let middleware = vec!["auth".to_string(), "audit".to_string()];
+
+        let req = Req::builder().method("GET").url("/").build();
+        let res = worker.run_chain(&middleware, "hello", req).unwrap();
+        assert_eq!(res.status, 401);
+        assert_eq!(res.body.as_deref(), Some("unauthorized"));
+
+        let headers = HashMap::from([("authorization".to_string(), "token".to_string())]);
+        let req = Req::builder()
+            .method("GET")
+            .url("/")
+            .headers(headers)
+            .build();
+        let res = worker.run_chain(&middleware, "hello", req).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body.as_deref(), Some("alice:1"));
+    }
+
     #[test]
     fn js_worker_should_work() {
         let code = r#"
@@ -163,4 +566,206 @@ mod tests {
         let ret = worker.run("hello", req).unwrap();
         assert_eq!(ret.status, 200);
     }
+
+    #[test]
+    fn js_worker_should_recycle_after_max_requests() {
+        let code = r#"
+(function(){
+    async function hello(req){
+        return { status: 200, headers: {}, body: "hello" };
+    }
+    return{hello:hello};
+})();
+        "#;
+        let policy = RecyclePolicy {
+            max_requests: Some(2),
+            ..Default::default()
+        };
+
+        let worker = JsWorker::try_new(code).unwrap();
+        assert!(worker.memory_used() > 0);
+        for i in 0..2 {
+            assert!(worker.should_recycle(&policy).is_none(), "request {i}");
+            let req = Req::builder().method("GET").url("/").build();
+            worker.run("hello", req).unwrap();
+        }
+        assert!(worker.should_recycle(&policy).is_some());
+    }
+
+    #[test]
+    fn js_worker_per_request_should_not_leak_globals() {
+        let code = r#"
+(function(){
+    async function count(req){
+        globalThis.counter = (globalThis.counter || 0) + 1;
+        return { status: 200, headers: {}, body: String(globalThis.counter) };
+    }
+    return{count:count};
+})();
+        "#;
+        let run = |worker: &JsWorker| {
+            let req = Req::builder().method("GET").url("/").build();
+            worker.run("count", req).unwrap().body.unwrap()
+        };
+
+        let shared = JsWorker::try_new(code).unwrap();
+        assert_eq!(run(&shared), "1");
+        assert_eq!(run(&shared), "2");
+
+        let options = WorkerOptions {
+            isolation: Isolation::PerRequest,
+            ..Default::default()
+        };
+        let isolated = JsWorker::with_options(code, &options).unwrap();
+        assert_eq!(run(&isolated), "1");
+        assert_eq!(run(&isolated), "1");
+    }
+
+    #[test]
+    fn js_worker_should_call_init_once() {
+        let code = r#"
+(function(){
+    let table = null;
+    let calls = 0;
+    function init(env){
+        calls += 1;
+        table = { greeting: env.greeting };
+    }
+    async function hello(req){
+        return { status: 200, headers: {}, body: table.greeting + " " + calls };
+    }
+    return{init:init,hello:hello};
+})();
+        "#;
+        let options = WorkerOptions {
+            env: HashMap::from([("greeting".to_string(), "hi".to_string())]),
+            ..Default::default()
+        };
+        let worker = JsWorker::with_options(code, &options).unwrap();
+        assert!(worker.init_time().is_some());
+        for _ in 0..2 {
+            let req = Req::builder().method("GET").url("/").build();
+            let res = worker.run("hello", req).unwrap();
+            assert_eq!(res.body.as_deref(), Some("hi 1"));
+        }
+
+        let worker = JsWorker::try_new(
+            r#"(function(){ async function hello(req){} return{hello:hello}; })();"#,
+        )
+        .unwrap();
+        assert!(worker.init_time().is_none());
+    }
+
+    #[tokio::test]
+    async fn js_worker_pool_should_survive_exceptions() -> anyhow::Result<()> {
+        let code = r#"
+(function(){
+    async function boom(req){
+        throw new Error("boom");
+    }
+    async function hello(req){
+        return { status: 200, headers: {}, body: "hello" };
+    }
+    return{boom:boom,hello:hello};
+})();
+        "#;
+        let pool = JsWorkerPool::try_new(1, code, WorkerOptions::default())?;
+        let run = |name: &'static str| {
+            let pool = &pool;
+            async move {
+                let req = Req::builder().method("GET").url("/").build();
+                pool.run(name, &[], req, None).await?.await?
+            }
+        };
+        let err = run("boom").await.unwrap_err();
+        assert!(err.to_string().contains("boom"), "{err}");
+        // the same worker serves the next request
+        assert_eq!(run("hello").await?.body.as_deref(), Some("hello"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn js_worker_pool_should_interrupt_runaway_handlers() -> anyhow::Result<()> {
+        let code = r#"
+(function(){
+    async function spin(req){
+        while(true){}
+    }
+    async function hello(req){
+        return { status: 200, headers: {}, body: "hello" };
+    }
+    return{spin:spin,hello:hello};
+})();
+        "#;
+        let pool = JsWorkerPool::try_new(1, code, WorkerOptions::default())?;
+        let req = || Req::builder().method("GET").url("/").build();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = pool
+            .run("spin", &[], req(), Some(deadline))
+            .await?
+            .await?
+            .unwrap_err();
+        assert!(err.to_string().contains("interrupted"), "{err}");
+        // the worker is free again, for the next request of its queue
+        let res = pool.run("hello", &[], req(), None).await?;
+        let res = tokio::time::timeout(Duration::from_secs(5), res).await???;
+        assert_eq!(res.body.as_deref(), Some("hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn js_worker_per_request_should_init_every_context() {
+        let code = r#"
+(function(){
+    let calls = 0;
+    let db = null;
+    function init(env){
+        calls += 1;
+        db = env.DB_URL;
+    }
+    async function hello(req){
+        calls += 1;
+        return { status: 200, headers: {}, body: `${calls}:${db}` };
+    }
+    return{init:init,hello:hello};
+})();
+        "#;
+        let options = WorkerOptions {
+            isolation: Isolation::PerRequest,
+            env: HashMap::from([("DB_URL".to_string(), "postgres://db".to_string())]),
+            ..Default::default()
+        };
+        let worker = JsWorker::with_options(code, &options).unwrap();
+        assert!(worker.init_time().is_some());
+        // every request sees the state of `init`, but not the one of the
+        // requests before
+        for _ in 0..2 {
+            let req = Req::builder().method("GET").url("/").build();
+            let res = worker.run("hello", req).unwrap();
+            assert_eq!(res.body.as_deref(), Some("2:postgres://db"));
+        }
+    }
+
+    #[test]
+    fn js_worker_should_report_syntax_errors() {
+        let err = JsWorker::try_new("(function(){ return { hello: ")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("bundle.js]:1:29"), "{err}");
+    }
+
+    #[test]
+    fn js_worker_pool_should_fail_if_init_fails() {
+        let code = r#"
+(function(){
+    async function init(env){
+        throw new Error("no database");
+    }
+    async function hello(req){}
+    return{init:init,hello:hello};
+})();
+        "#;
+        assert!(JsWorker::try_new(code).is_err());
+        assert!(JsWorkerPool::try_new(2, code, WorkerOptions::default()).is_err());
+    }
 }
@@ -1,19 +1,56 @@
+use std::time::Duration;
+
 use axum::{
-    http::{Method, StatusCode},
+    http::{
+        header::{ALLOW, RETRY_AFTER},
+        HeaderValue, Method, StatusCode,
+    },
     response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
 use thiserror::Error;
+use tracing::error;
+
+use crate::{allow_header, BodyError};
 
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Host not found: {0}")]
     HostNotFound(String),
 
+    #[error("Tenant not found: {0}")]
+    TenantNotFound(String),
+
     #[error("Path not found: {0}")]
     RoutePathNotFound(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Invalid param: {0}")]
+    InvalidParam(String),
+
     #[error("Method not found: {0}")]
-    RouteMethodNotAllowed(Method),
+    RouteMethodNotAllowed(Method, Vec<Method>),
+
+    #[error("No canary deployed for host: {0}")]
+    CanaryNotFound(String),
+
+    #[error("Too many requests, retry after {}s", .0.as_secs())]
+    RateLimited(Duration),
+
+    #[error("Payload too large, max {0} bytes")]
+    PayloadTooLarge(usize),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Invalid body: {}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "))]
+    InvalidBody(Vec<BodyError>),
+
+    #[error("Worker timeout: {0}")]
+    WorkerTimeout(String),
 
     #[error("Anyhow error: {0}")]
     Anyhow(#[from] anyhow::Error),
@@ -26,12 +63,59 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let code = match self {
             AppError::HostNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::TenantNotFound(_) => StatusCode::NOT_FOUND,
             AppError::RoutePathNotFound(_) => StatusCode::NOT_FOUND,
-            AppError::RouteMethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::InvalidParam(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::RouteMethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::CanaryNotFound(_) => StatusCode::CONFLICT,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::InvalidBody(_) => StatusCode::BAD_REQUEST,
+            AppError::WorkerTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
             AppError::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        (code, self.to_string()).into_response()
+        // clients need the pointers to show the errors next to the fields
+        if let AppError::InvalidBody(errors) = &self {
+            let body = json!({ "error": "Invalid body", "errors": errors });
+            return (code, Json(body)).into_response();
+        }
+        // e.g. the message and stack of a js exception, which would leak the
+        // code of the handler
+        if code == StatusCode::INTERNAL_SERVER_ERROR {
+            error!("{self:?}");
+            return (code, "Internal server error").into_response();
+        }
+        let mut res = (code, self.to_string()).into_response();
+        if let AppError::RouteMethodNotAllowed(_, allow) = &self {
+            if let Ok(v) = HeaderValue::from_str(&allow_header(allow)) {
+                res.headers_mut().insert(ALLOW, v);
+            }
+        }
+        if let AppError::RateLimited(retry_after) = &self {
+            res.headers_mut()
+                .insert(RETRY_AFTER, retry_after.as_secs().into());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn internal_errors_should_not_be_sent_to_clients() -> anyhow::Result<()> {
+        let err = AppError::from(anyhow::anyhow!(
+            "Error: secret\n    at handler (bundle.js:3)"
+        ));
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(body, "Internal server error");
+        Ok(())
     }
 }
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+
+/// matches any host, the tenant using it is the default tenant
+pub const DEFAULT_HOST: &str = "*";
+
+/// Maps request hosts to tenant names. Exact hosts win over wildcards like
+/// `*.example.com`, which match a single label, e.g. `a.example.com` but
+/// not `a.b.example.com`. `*` matches any other host.
+#[derive(Debug, Default)]
+pub struct HostTable {
+    exact: HashMap<String, String>,
+    /// by the domain after `*.`
+    wildcards: HashMap<String, String>,
+    default: Option<String>,
+}
+
+impl HostTable {
+    /// Add the host patterns of a tenant. A pattern already used by another
+    /// tenant is an error, the table should not be used after that.
+    pub fn insert(&mut self, tenant: &str, patterns: &[String]) -> anyhow::Result<()> {
+        for pattern in patterns {
+            let pattern = pattern.to_ascii_lowercase();
+            let existing = if pattern == DEFAULT_HOST {
+                self.default.replace(tenant.to_string())
+            } else if let Some(domain) = pattern.strip_prefix("*.") {
+                check_host(&pattern, domain)?;
+                self.wildcards
+                    .insert(domain.to_string(), tenant.to_string())
+            } else {
+                check_host(&pattern, &pattern)?;
+                self.exact.insert(pattern.clone(), tenant.to_string())
+            };
+            if let Some(other) = existing.filter(|other| other != tenant) {
+                bail!("host {pattern} is used by both {other} and {tenant}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the tenant serving `host`, which must have no port.
+    pub fn resolve(&self, host: &str) -> Option<&str> {
+        let host = host.to_ascii_lowercase();
+        if let Some(tenant) = self.exact.get(&host) {
+            return Some(tenant.as_str());
+        }
+        host.split_once('.')
+            .and_then(|(_, domain)| self.wildcards.get(domain))
+            .or(self.default.as_ref())
+            .map(|s| s.as_str())
+    }
+}
+
+fn check_host(pattern: &str, host: &str) -> anyhow::Result<()> {
+    if host.is_empty() || host.contains('*') {
+        bail!("invalid host pattern: {pattern}");
+    }
+    Ok(())
+}
+
+/// Strip the port of a `Host` header value, e.g. `example.com:3000` or
+/// `[::1]:3000`.
+pub fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split_once(']').map_or(host, |(ip, _)| ip);
+    }
+    host.split_once(':').map_or(host, |(host, _)| host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn host_table_should_resolve_patterns() -> anyhow::Result<()> {
+        let mut table = HostTable::default();
+        table.insert("app", &hosts(&["example.com", "www.example.com"]))?;
+        table.insert("tenants", &hosts(&["*.example.com"]))?;
+        table.insert("fallback", &hosts(&["*"]))?;
+
+        assert_eq!(table.resolve("example.com"), Some("app"));
+        assert_eq!(table.resolve("WWW.example.com"), Some("app"));
+        assert_eq!(table.resolve("acme.example.com"), Some("tenants"));
+        assert_eq!(table.resolve("a.b.example.com"), Some("fallback"));
+        assert_eq!(table.resolve("localhost"), Some("fallback"));
+        Ok(())
+    }
+
+    #[test]
+    fn host_table_should_reject_conflicts() {
+        let mut table = HostTable::default();
+        table.insert("a", &hosts(&["*.example.com"])).unwrap();
+        // the same tenant may list a host twice
+        table.insert("a", &hosts(&["*.example.com"])).unwrap();
+        let err = table.insert("b", &hosts(&["*.example.com"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "host *.example.com is used by both a and b"
+        );
+        assert!(table.insert("c", &hosts(&["api.*.com"])).is_err());
+        assert_eq!(table.resolve("other.com"), None);
+    }
+
+    #[test]
+    fn strip_port_should_work() {
+        assert_eq!(strip_port("example.com:3000"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:3000"), "::1");
+    }
+}
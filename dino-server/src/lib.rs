@@ -1,166 +1,327 @@
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
-use axum::body::Bytes;
-use axum::extract::{Host, Query, State};
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Extension, Query, Request, State};
+use axum::http::header::{ALLOW, HOST, LOCATION};
 use axum::http::request::Parts;
-use axum::http::Response;
+use axum::http::{HeaderValue, Method, Response, StatusCode};
+use axum::middleware::{from_fn_with_state, Next};
 use axum::response::IntoResponse;
 use axum::routing::any;
 use axum::Router;
+use body::read_body;
 use dashmap::DashMap;
 use indexmap::IndexMap;
-use matchit::Match;
-use middleware::ServerTimeLayer;
+use middleware::{AuthLayer, CacheLayer, CorsLayer, RateLimitLayer, ServerTimeLayer};
 use tokio::net::TcpListener;
 use tracing::info;
 
+mod admin;
+mod assets;
+mod body;
 mod config;
+mod config_error;
 mod engine;
 mod error;
+mod hosts;
 mod middleware;
 mod router;
+mod stats;
+mod tenant;
 mod worker_pool;
 
+pub use self::admin::*;
+pub use self::assets::*;
+pub use self::body::{BodyError, DEFAULT_MAX_BODY_SIZE};
 pub use self::config::*;
+pub use self::config_error::ConfigError;
 pub use self::engine::*;
 pub use self::error::AppError;
+pub use self::hosts::*;
 pub use self::router::*;
+pub use self::stats::*;
+pub use self::tenant::*;
 pub use self::worker_pool::*;
 
-type ProjectRoutes = IndexMap<String, Vec<ProjectRoute>>;
+type ProjectRoutes = IndexMap<String, RouteNode>;
+
+/// header set by proxies to the host requested by the client
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+/// header set by proxies to the addresses the request came through
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// tenants are mounted at `/t/<tenant>` in `TenantMode::PathPrefix`
+const TENANT_PATH_PREFIX: &str = "/t/";
 
 #[derive(Clone)]
 pub struct AppState {
-    routers: DashMap<String, SwappableAppRouter>,
-    worker_pools: DashMap<String, SwappableWorkerPool>,
+    tenants: Arc<DashMap<String, SwappableTenant>>,
+    hosts: Arc<HostTable>,
+    options: Arc<ServerOptions>,
 }
 
-#[derive(Clone)]
-pub struct TenentRouter {
-    host: String,
-    router: SwappableAppRouter,
+/// The tenant version serving a request and the path within the tenant,
+/// resolved once before the layers which depend on them.
+pub struct RequestTarget {
+    pub version: Arc<TenantVersion>,
+    /// path the tenant is mounted at, empty unless `TenantMode::PathPrefix`
+    pub base: String,
+    pub path: String,
+    /// address of the client, from `X-Forwarded-For` behind trusted proxies
+    pub client: IpAddr,
 }
 
-#[derive(Clone)]
-pub struct TenentWorkerPool {
-    host: String,
-    pool: SwappableWorkerPool,
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// proxies whose `X-Forwarded-Host` header is used to pick the tenant
+    pub trusted_proxies: Vec<IpAddr>,
+    pub tenant_mode: TenantMode,
+}
+
+/// How the tenant of a request is picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TenantMode {
+    /// by the request host and the `hosts` of the tenants
+    #[default]
+    Host,
+    /// by the path prefix `/t/<tenant>`, which is removed before routing
+    PathPrefix,
 }
 
 pub async fn start_server(
     port: u16,
-    routers: Vec<TenentRouter>,
-    worker_pools: Vec<TenentWorkerPool>,
+    tenants: Vec<SwappableTenant>,
+    options: ServerOptions,
 ) -> anyhow::Result<()> {
     let addr = format!("0.0.0.0:{port}");
     let listener = TcpListener::bind(addr).await?;
 
     info!("Listening on {}", listener.local_addr()?);
 
-    let routes = DashMap::new();
-    for TenentRouter { host, router } in routers {
-        routes.insert(host, router);
-    }
-    let pools = DashMap::new();
-    for TenentWorkerPool { host, pool } in worker_pools {
-        pools.insert(host, pool);
-    }
-    let state = AppState::new(routes, pools);
+    let state = AppState::try_new(tenants, options)?;
     let app = Router::new()
+        .route("/", any(handler))
         .route("/*path", any(handler))
+        .layer(CacheLayer)
+        .layer(RateLimitLayer)
+        .layer(AuthLayer)
+        .layer(CorsLayer)
+        .layer(from_fn_with_state(state.clone(), resolve_target))
         .layer(ServerTimeLayer)
         .with_state(state);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, app).await?;
 
     Ok(())
 }
 
-async fn handler(
+/// Pick the tenant version of a request, for the layers and the handler.
+async fn resolve_target(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response<Body>, AppError> {
+    let (parts, body) = request.into_parts();
+    let uri = &parts.uri;
+    // `base` is the path the tenant is mounted at, `path` the rest
+    let (tenant, base, path) = match state.options.tenant_mode {
+        TenantMode::Host => {
+            let host = request_host(&parts, &peer, &state.options).unwrap_or_default();
+            (state.resolve(host)?, "", uri.path())
+        }
+        TenantMode::PathPrefix => {
+            let (name, base, path) = split_tenant_prefix(uri.path())
+                .ok_or_else(|| AppError::RoutePathNotFound(uri.path().to_string()))?;
+            (state.tenant(name)?, base, path)
+        }
+    };
+    // routes and worker pool must come from the same tenant version
+    let target = RequestTarget {
+        version: tenant.load().select(&parts.headers),
+        base: base.to_string(),
+        path: path.to_string(),
+        client: client_ip(&parts, &peer, &state.options),
+    };
+    request = Request::from_parts(parts, body);
+    request.extensions_mut().insert(Arc::new(target));
+    Ok(next.run(request).await)
+}
+
+async fn handler(
+    Extension(target): Extension<Arc<RequestTarget>>,
     parts: Parts,
-    Host(host): Host,
     Query(query): Query<HashMap<String, String>>,
-    body: Option<Bytes>,
+    body: Body,
 ) -> Result<impl IntoResponse, AppError> {
-    let router = get_router_by_host(host.clone(), state.clone())?;
-    let matched = router.match_it(parts.method.clone(), parts.uri.path())?;
-    let req = assemble_req(&matched, &parts, query, body)?;
-
-    // TODO: build a worker pool, and send req via mpsc channel and get res from oneshot channel
-    // but if code changed we need to recreate the worker pool
-    // let worker = JsWorker::try_new(&router.code)?;
-    let handler = matched.value;
-
-    let worker_pool = get_worker_pool_by_host(host, state)?;
-    let res = worker_pool.run(handler, req).await?;
-    // let res = worker.run(handler, req)?;
-    Ok(Response::from(res))
-}
+    let uri = &parts.uri;
+    let (version, base, path) = (&target.version, target.base.as_str(), target.path.as_str());
+    // redirects, assets, rewrites and routes, in that order. Only the
+    // routes need a worker
+    if let Some((status, mut location)) = version.router.redirect(path, uri.query()) {
+        // a local target is within the tenant
+        if location.starts_with('/') && !location.starts_with("//") {
+            location = format!("{base}{location}");
+        }
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = status;
+        if let Ok(v) = HeaderValue::from_str(&location) {
+            res.headers_mut().insert(LOCATION, v);
+        }
+        return Ok(res);
+    }
+    if let Some(res) = version.assets.serve(&parts, path) {
+        return Ok(res);
+    }
+    let rewritten = version.router.rewrite(path);
+    if let Some(path) = &rewritten {
+        if let Some(res) = version.assets.serve(&parts, path) {
+            return Ok(res);
+        }
+    }
+    let path = rewritten.as_deref().unwrap_or(path);
+    if parts.method == Method::OPTIONS {
+        if let Some(allow) = version.router.auto_options(path) {
+            return Ok(allow_response(StatusCode::NO_CONTENT, &allow));
+        }
+    }
 
-impl AppState {
-    pub fn new(
-        routers: DashMap<String, SwappableAppRouter>,
-        pools: DashMap<String, SwappableWorkerPool>,
-    ) -> Self {
-        Self {
-            routers,
-            worker_pools: pools,
+    let matched = version.router.match_it(parts.method.clone(), path)?;
+    // checked before any js runs
+    let limit = version
+        .config
+        .max_body_size
+        .unwrap_or(DEFAULT_MAX_BODY_SIZE);
+    let body = read_body(&parts.headers, body, matched.value, limit).await?;
+    let req = assemble_req(&matched, &parts, base, query, Some(body))?;
+    let route = matched.value;
+
+    let pool = version.pool(route.pool.as_deref());
+    let res = pool
+        .run(&route.handler, &route.middleware, req, route.timeout)
+        .await;
+    version.metrics.record(&res);
+
+    let mut res = Response::from(res?);
+    if !matched.allow.is_empty() && !res.headers().contains_key(ALLOW) {
+        if let Ok(v) = HeaderValue::from_str(&allow_header(&matched.allow)) {
+            res.headers_mut().insert(ALLOW, v);
         }
     }
+    // HEAD may be handled by a GET handler, the body is never sent
+    if parts.method == Method::HEAD {
+        *res.body_mut() = Body::empty();
+    }
+    Ok(res)
 }
 
-impl TenentRouter {
-    pub fn new(host: impl Into<String>, router: SwappableAppRouter) -> Self {
-        Self {
-            host: host.into(),
-            router,
-        }
+fn allow_response(status: StatusCode, allow: &[Method]) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    if let Ok(v) = HeaderValue::from_str(&allow_header(allow)) {
+        res.headers_mut().insert(ALLOW, v);
     }
+    res
 }
 
-impl TenentWorkerPool {
-    pub fn new(host: impl Into<String>, pool: SwappableWorkerPool) -> Self {
-        Self {
-            host: host.into(),
-            pool,
+impl AppState {
+    /// Tenants are served on the `hosts` of their config when the state is
+    /// created, changes of `hosts` in a swapped config need a restart.
+    pub fn try_new(tenants: Vec<SwappableTenant>, options: ServerOptions) -> anyhow::Result<Self> {
+        let map = DashMap::new();
+        let mut hosts = HostTable::default();
+        for tenant in tenants {
+            hosts.insert(&tenant.host, &tenant.load().stable.config.hosts)?;
+            map.insert(tenant.host.clone(), tenant);
         }
+        Ok(Self {
+            tenants: Arc::new(map),
+            hosts: Arc::new(hosts),
+            options: Arc::new(options),
+        })
+    }
+
+    /// The tenant with the given name.
+    pub fn tenant(&self, name: &str) -> Result<SwappableTenant, AppError> {
+        self.tenants
+            .get(name)
+            .map(|t| t.clone())
+            .ok_or_else(|| AppError::TenantNotFound(name.to_string()))
+    }
+
+    /// The tenant serving the request host `host`.
+    pub fn resolve(&self, host: &str) -> Result<SwappableTenant, AppError> {
+        info!("host: {:?}", host);
+        let name = self
+            .hosts
+            .resolve(host)
+            .ok_or_else(|| AppError::HostNotFound(host.to_string()))?;
+        self.tenant(name)
     }
 }
 
-fn get_router_by_host(mut host: String, state: AppState) -> Result<AppRouter, AppError> {
-    _ = host.split_off(host.find(':').unwrap_or(host.len()));
-    info!("host: {:?}", host);
-    let router = state
-        .routers
-        .get(&host)
-        .ok_or(AppError::HostNotFound(host))?
-        .load();
-    Ok(router)
+/// Split `/t/<tenant>/rest` into the tenant name, the mount base
+/// `/t/<tenant>` and the path within the tenant, `/rest`.
+fn split_tenant_prefix(path: &str) -> Option<(&str, &str, &str)> {
+    let rest = path.strip_prefix(TENANT_PATH_PREFIX)?;
+    let name = rest.split('/').next().filter(|name| !name.is_empty())?;
+    let (base, rest) = path.split_at(TENANT_PATH_PREFIX.len() + name.len());
+    Some((name, base, if rest.is_empty() { "/" } else { rest }))
 }
 
-fn get_worker_pool_by_host(mut host: String, state: AppState) -> Result<WorkerPool, AppError> {
-    _ = host.split_off(host.find(':').unwrap_or(host.len()));
-    info!("host: {:?}", host);
-    let pool = state
-        .worker_pools
-        .get(&host)
-        .ok_or(AppError::HostNotFound(host))?
-        .load();
-    Ok(pool)
+/// Host of the request, without port. `X-Forwarded-Host` is only used for
+/// requests from trusted proxies, anyone else could pick any tenant with it.
+fn request_host<'a>(
+    parts: &'a Parts,
+    peer: &SocketAddr,
+    options: &ServerOptions,
+) -> Option<&'a str> {
+    let header = |name: &str| parts.headers.get(name).and_then(|v| v.to_str().ok());
+    let forwarded = if options.trusted_proxies.contains(&peer.ip()) {
+        // the first value is the host the client requested
+        header(X_FORWARDED_HOST).and_then(|v| v.split(',').next())
+    } else {
+        None
+    };
+    let host = forwarded
+        .or_else(|| header(HOST.as_str()))
+        .or_else(|| parts.uri.host())?;
+    Some(strip_port(host.trim()))
+}
+
+/// Address of the client. Trusted proxies add the address they got the
+/// request from to `X-Forwarded-For`, the client is the last one which is
+/// not a trusted proxy. Anything before it could be made up by the client.
+fn client_ip(parts: &Parts, peer: &SocketAddr, options: &ServerOptions) -> IpAddr {
+    let trusted = |ip: &IpAddr| options.trusted_proxies.contains(ip);
+    if !trusted(&peer.ip()) {
+        return peer.ip();
+    }
+    let forwarded = parts
+        .headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|ip| !trusted(ip))
+        .unwrap_or(peer.ip())
 }
 
 fn assemble_req(
-    matched: &Match<&str>,
+    matched: &RouteMatch,
     parts: &Parts,
+    base: &str,
     query: HashMap<String, String>,
     body: Option<Bytes>,
 ) -> Result<Req, AppError> {
-    let params: HashMap<String, String> = matched
-        .params
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
+    let params = matched.params.clone();
 
     let headers: HashMap<String, String> = parts
         .headers
@@ -173,11 +334,62 @@ fn assemble_req(
     let req = Req::builder()
         .method(parts.method.to_string())
         .url(parts.uri.to_string())
+        .base(base)
         .headers(headers)
         .params(params)
         .query(query)
         .body(body)
+        .auth(parts.extensions.get::<Identity>().cloned())
         .build();
 
     Ok(req)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_tenant_prefix_should_work() {
+        assert_eq!(
+            split_tenant_prefix("/t/acme/api/users"),
+            Some(("acme", "/t/acme", "/api/users"))
+        );
+        assert_eq!(
+            split_tenant_prefix("/t/acme"),
+            Some(("acme", "/t/acme", "/"))
+        );
+        assert_eq!(
+            split_tenant_prefix("/t/acme/"),
+            Some(("acme", "/t/acme", "/"))
+        );
+        assert_eq!(split_tenant_prefix("/t/"), None);
+        assert_eq!(split_tenant_prefix("/api/users"), None);
+    }
+
+    #[test]
+    fn client_ip_should_skip_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let options = ServerOptions {
+            trusted_proxies: vec![proxy, "10.0.0.2".parse().unwrap()],
+            ..Default::default()
+        };
+        let parts = |forwarded: &str| {
+            axum::http::Request::builder()
+                .header(X_FORWARDED_FOR, forwarded)
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+        let client = "203.0.113.7".parse::<IpAddr>().unwrap();
+        let forwarded = parts("198.51.100.1, 203.0.113.7, 10.0.0.2");
+        let ip = client_ip(&forwarded, &SocketAddr::new(proxy, 80), &options);
+        assert_eq!(ip, client);
+        // anyone else can not pick their address
+        let ip = client_ip(&forwarded, &SocketAddr::new(client, 80), &options);
+        assert_eq!(ip, client);
+        let ip = client_ip(&parts("10.0.0.2"), &SocketAddr::new(proxy, 80), &options);
+        assert_eq!(ip, proxy);
+    }
+}
@@ -0,0 +1,544 @@
+use anyhow::{bail, Context as _};
+use axum::{
+    extract::Request,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderMap, HeaderName, HeaderValue,
+    },
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use indexmap::IndexMap;
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use crate::{AppError, AuthConfig, Identity, JwtAlgorithm, ProjectConfig, RequestTarget};
+
+/// The `secrets` file of a tenant.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Secrets {
+    /// api keys by name
+    #[serde(default)]
+    pub api_keys: IndexMap<String, String>,
+    /// basic auth passwords by user
+    #[serde(default)]
+    pub users: IndexMap<String, String>,
+}
+
+/// The keys and secrets the routes of a tenant version are authenticated
+/// with, loaded from the `jwks` and `secrets` files of the config.
+#[derive(Default)]
+pub struct Credentials {
+    secrets: Secrets,
+    /// decoding keys of the jwks, by `kid`, with the algorithms they verify
+    keys: Vec<(Option<String>, &'static [Algorithm], DecodingKey)>,
+}
+
+/// An `auth` block of the config, ready to check requests.
+#[derive(Debug, Clone)]
+pub struct AuthPolicy {
+    jwt: Option<(Vec<Algorithm>, Validation)>,
+    api_key: Option<(HeaderName, Vec<String>)>,
+    basic: Option<(String, Vec<String>)>,
+}
+
+/// Rejects requests to routes with `auth` which none of its schemes
+/// accepts, and sets the `Identity` of the others. Needs the
+/// `RequestTarget` of the request.
+#[derive(Clone)]
+pub struct AuthLayer;
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthMiddleware { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthMiddleware<S> {
+    inner: S,
+}
+
+impl Credentials {
+    pub fn load(config: &ProjectConfig) -> anyhow::Result<Self> {
+        let secrets = match &config.secrets {
+            Some(file) => {
+                let content = fs::read_to_string(file)
+                    .with_context(|| format!("failed to read secrets {file}"))?;
+                serde_yaml::from_str(&content)
+                    .with_context(|| format!("failed to parse secrets {file}"))?
+            }
+            None => Secrets::default(),
+        };
+        let keys = match &config.jwks {
+            Some(file) => {
+                let content = fs::read_to_string(file)
+                    .with_context(|| format!("failed to read jwks {file}"))?;
+                let jwks: JwkSet = serde_json::from_str(&content)
+                    .with_context(|| format!("failed to parse jwks {file}"))?;
+                jwks.keys
+                    .iter()
+                    .map(|jwk| {
+                        let key = DecodingKey::from_jwk(jwk)?;
+                        Ok((jwk.common.key_id.clone(), key_family(&jwk.algorithm), key))
+                    })
+                    .collect::<anyhow::Result<_>>()
+                    .with_context(|| format!("invalid key in jwks {file}"))?
+            }
+            None => vec![],
+        };
+        Ok(Self { secrets, keys })
+    }
+
+    /// Check that the credentials an `auth` block needs are configured.
+    pub fn check(&self, config: &AuthConfig) -> anyhow::Result<()> {
+        if config.jwt.is_some() && self.keys.is_empty() {
+            bail!("jwt auth needs the keys of `jwks`");
+        }
+        if let Some(api_key) = &config.api_key {
+            if self.secrets.api_keys.is_empty() {
+                bail!("api key auth needs `api_keys` in the `secrets` file");
+            }
+            if let Some(key) = api_key
+                .keys
+                .iter()
+                .find(|k| !self.secrets.api_keys.contains_key(*k))
+            {
+                bail!("api key `{key}` is not in the secrets file");
+            }
+        }
+        if let Some(basic) = &config.basic {
+            if self.secrets.users.is_empty() {
+                bail!("basic auth needs `users` in the `secrets` file");
+            }
+            if let Some(user) = basic
+                .users
+                .iter()
+                .find(|u| !self.secrets.users.contains_key(*u))
+            {
+                bail!("user `{user}` is not in the secrets file");
+            }
+        }
+        Ok(())
+    }
+
+    fn key(&self, kid: Option<&str>) -> Option<(&'static [Algorithm], &DecodingKey)> {
+        let (_, family, key) = match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|(id, ..)| id.as_deref() == Some(kid))?,
+            // without a `kid` only a single key is unambiguous
+            None if self.keys.len() == 1 => &self.keys[0],
+            None => return None,
+        };
+        Some((family, key))
+    }
+}
+
+impl AuthPolicy {
+    pub fn try_new(config: &AuthConfig) -> anyhow::Result<Self> {
+        if config.jwt.is_none() && config.api_key.is_none() && config.basic.is_none() {
+            bail!("auth needs one of `jwt`, `api_key` or `basic`");
+        }
+        let jwt = config.jwt.as_ref().map(|jwt| {
+            let algorithms: Vec<Algorithm> = jwt.algorithms.iter().map(|a| a.into()).collect();
+            // the algorithm is set per token, jsonwebtoken rejects a key if
+            // any of `validation.algorithms` is of another family
+            let mut validation = Validation::default();
+            validation.leeway = jwt.leeway;
+            validation.validate_nbf = true;
+            if let Some(issuer) = &jwt.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            match &jwt.audience {
+                Some(audience) => validation.set_audience(&[audience]),
+                None => validation.validate_aud = false,
+            }
+            (algorithms, validation)
+        });
+        let api_key = match &config.api_key {
+            Some(api_key) => {
+                let header = HeaderName::try_from(api_key.header.as_str())
+                    .with_context(|| format!("invalid api key header {}", api_key.header))?;
+                Some((header, api_key.keys.clone()))
+            }
+            None => None,
+        };
+        let basic = config
+            .basic
+            .as_ref()
+            .map(|basic| (basic.realm.clone(), basic.users.clone()));
+        Ok(Self {
+            jwt,
+            api_key,
+            basic,
+        })
+    }
+
+    /// The identity of a request, if any scheme of the policy accepts it.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        credentials: &Credentials,
+    ) -> Result<Identity, String> {
+        let authorization = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let scheme = authorization.and_then(|v| v.split_once(' '));
+        let mut error = "missing credentials".to_string();
+
+        if let (Some((algorithms, validation)), Some((bearer, token))) = (&self.jwt, scheme) {
+            if bearer.eq_ignore_ascii_case("bearer") {
+                match verify_jwt(token.trim(), algorithms, validation, credentials) {
+                    Ok(identity) => return Ok(identity),
+                    Err(e) => error = e,
+                }
+            }
+        }
+        if let Some((header, names)) = &self.api_key {
+            if let Some(key) = headers.get(header) {
+                let name = credentials
+                    .secrets
+                    .api_keys
+                    .iter()
+                    .filter(|(name, _)| names.is_empty() || names.contains(name))
+                    .find(|(_, secret)| constant_time_eq(key.as_bytes(), secret.as_bytes()))
+                    .map(|(name, _)| name);
+                match name {
+                    Some(name) => return Ok(identity("api_key", name.clone())),
+                    None => error = "invalid api key".to_string(),
+                }
+            }
+        }
+        if let (Some((_, users)), Some((basic, encoded))) = (&self.basic, scheme) {
+            if basic.eq_ignore_ascii_case("basic") {
+                let decoded = STANDARD
+                    .decode(encoded.trim())
+                    .ok()
+                    .and_then(|v| String::from_utf8(v).ok());
+                let valid = decoded.as_deref().and_then(|v| v.split_once(':')).filter(
+                    |(user, password)| {
+                        (users.is_empty() || users.iter().any(|u| u == *user))
+                            && credentials.secrets.users.get(*user).is_some_and(|p| {
+                                constant_time_eq(p.as_bytes(), password.as_bytes())
+                            })
+                    },
+                );
+                match valid {
+                    Some((user, _)) => return Ok(identity("basic", user.to_string())),
+                    None => error = "invalid user or password".to_string(),
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// `WWW-Authenticate` challenges of the schemes of the policy.
+    fn challenges(&self) -> Vec<HeaderValue> {
+        let mut ret = vec![];
+        if self.jwt.is_some() {
+            ret.push(HeaderValue::from_static("Bearer"));
+        }
+        if let Some((realm, _)) = &self.basic {
+            let realm = realm.replace('"', "");
+            if let Ok(v) = HeaderValue::from_str(&format!("Basic realm=\"{realm}\"")) {
+                ret.push(v);
+            }
+        }
+        ret
+    }
+}
+
+impl From<&JwtAlgorithm> for Algorithm {
+    fn from(alg: &JwtAlgorithm) -> Self {
+        match alg {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+            JwtAlgorithm::EdDSA => Algorithm::EdDSA,
+        }
+    }
+}
+
+fn verify_jwt(
+    token: &str,
+    algorithms: &[Algorithm],
+    validation: &Validation,
+    credentials: &Credentials,
+) -> Result<Identity, String> {
+    let header = jsonwebtoken::decode_header(token).map_err(|e| format!("invalid jwt: {e}"))?;
+    // the token must not pick a weaker algorithm than the route accepts
+    if !algorithms.contains(&header.alg) {
+        return Err(format!("jwt algorithm {:?} is not accepted", header.alg));
+    }
+    let (family, key) = credentials
+        .key(header.kid.as_deref())
+        .ok_or_else(|| "unknown jwt key".to_string())?;
+    if !family.contains(&header.alg) {
+        return Err(format!(
+            "jwt algorithm {:?} does not match the key",
+            header.alg
+        ));
+    }
+    let mut validation = validation.clone();
+    validation.algorithms = vec![header.alg];
+    let data =
+        jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(token, key, &validation)
+            .map_err(|e| format!("invalid jwt: {e}"))?;
+    let subject = data
+        .claims
+        .get("sub")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    Ok(Identity {
+        scheme: "jwt",
+        subject,
+        claims: data.claims,
+    })
+}
+
+/// The algorithms a key of the jwks can verify, by its type.
+fn key_family(params: &AlgorithmParameters) -> &'static [Algorithm] {
+    match params {
+        AlgorithmParameters::OctetKey(_) => &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+        AlgorithmParameters::RSA(_) => &[
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(_) => &[Algorithm::ES256, Algorithm::ES384],
+        AlgorithmParameters::OctetKeyPair(_) => &[Algorithm::EdDSA],
+    }
+}
+
+fn identity(scheme: &'static str, subject: String) -> Identity {
+    Identity {
+        scheme,
+        subject,
+        claims: Default::default(),
+    }
+}
+
+/// Compare secrets in a time which does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl<S> Service<Request> for AuthMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let Some(target) = request.extensions().get::<Arc<RequestTarget>>().cloned() else {
+            return Box::pin(self.inner.call(request));
+        };
+        let version = &target.version;
+        let path = version.router.rewrite(&target.path);
+        let path = path.as_deref().unwrap_or(&target.path);
+        let Some(policy) = version.router.auth(request.method(), path) else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        match policy.authenticate(request.headers(), &version.credentials) {
+            Ok(identity) => {
+                request.extensions_mut().insert(identity);
+                Box::pin(self.inner.call(request))
+            }
+            Err(e) => {
+                let mut response = AppError::Unauthorized(e).into_response();
+                for challenge in policy.challenges() {
+                    response.headers_mut().append(WWW_AUTHENTICATE, challenge);
+                }
+                Box::pin(async move { Ok(response) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"dino-test-secret-key-0123456789ab";
+
+    fn credentials() -> Credentials {
+        let config: ProjectConfig = serde_yaml::from_str(
+            "name: auth\nsecrets: fixtures/secrets.yml\njwks: fixtures/jwks.json\nroutes: {}",
+        )
+        .unwrap();
+        Credentials::load(&config).unwrap()
+    }
+
+    fn auth_policy(yaml: &str) -> AuthPolicy {
+        let config: AuthConfig = serde_yaml::from_str(yaml).unwrap();
+        credentials().check(&config).unwrap();
+        AuthPolicy::try_new(&config).unwrap()
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::try_from(name).unwrap(),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    fn token(claims: serde_json::Value) -> String {
+        sign(Algorithm::HS256, "test", &claims)
+    }
+
+    fn sign(alg: Algorithm, kid: &str, claims: &serde_json::Value) -> String {
+        let key = match alg {
+            Algorithm::HS256 => EncodingKey::from_secret(SECRET),
+            Algorithm::RS256 => {
+                EncodingKey::from_rsa_pem(&fs::read("fixtures/keys/rs256.pem").unwrap()).unwrap()
+            }
+            Algorithm::EdDSA => {
+                EncodingKey::from_ed_pem(&fs::read("fixtures/keys/ed25519.pem").unwrap()).unwrap()
+            }
+            _ => unreachable!(),
+        };
+        let mut header = Header::new(alg);
+        header.kid = Some(kid.to_string());
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    #[test]
+    fn jwt_auth_should_verify_claims() {
+        let policy = auth_policy("jwt:\n  algorithms: [HS256]\n  issuer: dino");
+        let credentials = credentials();
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+
+        let jwt = token(json!({ "sub": "alice", "iss": "dino", "exp": exp, "role": "admin" }));
+        let identity = policy
+            .authenticate(
+                &headers("authorization", &format!("Bearer {jwt}")),
+                &credentials,
+            )
+            .unwrap();
+        assert_eq!(identity.scheme, "jwt");
+        assert_eq!(identity.subject, "alice");
+        assert_eq!(identity.claims["role"], "admin");
+
+        let jwt = token(json!({ "sub": "alice", "iss": "other", "exp": exp }));
+        let auth = headers("authorization", &format!("Bearer {jwt}"));
+        assert!(policy.authenticate(&auth, &credentials).is_err());
+        let jwt = token(json!({ "sub": "alice", "iss": "dino", "exp": exp - 3600 }));
+        let auth = headers("authorization", &format!("Bearer {jwt}"));
+        assert!(policy.authenticate(&auth, &credentials).is_err());
+
+        // HS256 must be accepted explicitly
+        let policy = auth_policy("jwt: {}");
+        let jwt = token(json!({ "sub": "alice", "exp": exp }));
+        let auth = headers("authorization", &format!("Bearer {jwt}"));
+        assert!(policy.authenticate(&auth, &credentials).is_err());
+    }
+
+    #[test]
+    fn jwt_auth_should_accept_each_configured_algorithm() {
+        let credentials = credentials();
+        let claims = json!({ "sub": "alice", "exp": jsonwebtoken::get_current_timestamp() + 60 });
+        let bearer = |jwt: String| headers("authorization", &format!("Bearer {jwt}"));
+        let rs256 = sign(Algorithm::RS256, "rsa", &claims);
+        let eddsa = sign(Algorithm::EdDSA, "ed25519", &claims);
+        let hs256 = sign(Algorithm::HS256, "test", &claims);
+
+        // the default is RS256 and EdDSA
+        let policy = auth_policy("jwt: {}");
+        let identity = policy.authenticate(&bearer(rs256.clone()), &credentials);
+        assert_eq!(identity.unwrap().subject, "alice");
+        let identity = policy.authenticate(&bearer(eddsa.clone()), &credentials);
+        assert_eq!(identity.unwrap().subject, "alice");
+        assert!(policy
+            .authenticate(&bearer(hs256.clone()), &credentials)
+            .is_err());
+
+        let policy = auth_policy("jwt:\n  algorithms: [HS256, RS256]");
+        assert!(policy.authenticate(&bearer(hs256), &credentials).is_ok());
+        assert!(policy.authenticate(&bearer(rs256), &credentials).is_ok());
+        assert!(policy.authenticate(&bearer(eddsa), &credentials).is_err());
+
+        // a token can't pick a key of another family
+        let forged = sign(Algorithm::HS256, "rsa", &claims);
+        let err = policy
+            .authenticate(&bearer(forged), &credentials)
+            .unwrap_err();
+        assert_eq!(err, "jwt algorithm HS256 does not match the key");
+    }
+
+    #[test]
+    fn api_key_and_basic_auth_should_work() {
+        let policy = auth_policy("api_key:\n  keys: [ci]\nbasic:\n  realm: admin");
+        let credentials = credentials();
+
+        let identity = policy
+            .authenticate(&headers("x-api-key", "ci-key-123"), &credentials)
+            .unwrap();
+        assert_eq!(
+            (identity.scheme, identity.subject.as_str()),
+            ("api_key", "ci")
+        );
+        // only the listed keys
+        let auth = headers("x-api-key", "deploy-key-456");
+        assert!(policy.authenticate(&auth, &credentials).is_err());
+
+        let basic = format!("Basic {}", STANDARD.encode("alice:wonderland"));
+        let identity = policy
+            .authenticate(&headers("authorization", &basic), &credentials)
+            .unwrap();
+        assert_eq!(
+            (identity.scheme, identity.subject.as_str()),
+            ("basic", "alice")
+        );
+        let basic = format!("Basic {}", STANDARD.encode("alice:guess"));
+        let err = policy
+            .authenticate(&headers("authorization", &basic), &credentials)
+            .unwrap_err();
+        assert_eq!(err, "invalid user or password");
+        assert_eq!(
+            policy.authenticate(&HeaderMap::new(), &credentials),
+            Err("missing credentials".to_string())
+        );
+        assert_eq!(policy.challenges(), ["Basic realm=\"admin\""]);
+    }
+
+    #[test]
+    fn credentials_check_should_work() {
+        let config: AuthConfig = serde_yaml::from_str("api_key:\n  keys: [nope]").unwrap();
+        assert!(credentials().check(&config).is_err());
+        let config: AuthConfig = serde_yaml::from_str("basic: {}").unwrap();
+        assert!(Credentials::default().check(&config).is_err());
+        let config: AuthConfig = serde_yaml::from_str("{}").unwrap();
+        assert!(AuthPolicy::try_new(&config).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use anyhow::Context as _;
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::Request,
+    http::{
+        header::{AGE, AUTHORIZATION, CACHE_CONTROL, SET_COOKIE, VARY},
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    response::Response,
+};
+use lru::LruCache;
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+use super::CACHE_STATUS_HEADER;
+use crate::{CacheConfig, Identity, RequestTarget};
+
+/// larger responses are not cached
+const MAX_CACHED_BODY: u64 = 1024 * 1024;
+
+/// The cached responses of a tenant version, least recently used first out.
+/// A new version starts with an empty cache, so a deploy purges it.
+pub struct ResponseCache {
+    entries: Mutex<LruCache<String, CachedResponse>>,
+}
+
+/// A `cache` block of a route.
+#[derive(Debug, Clone)]
+pub struct CacheRule {
+    ttl: Duration,
+    vary: Vec<HeaderName>,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Serves `GET` and `HEAD` requests to routes with `cache` from the
+/// `ResponseCache` of their tenant version. Needs the `RequestTarget` of
+/// the request.
+#[derive(Clone)]
+pub struct CacheLayer;
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = CacheMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheMiddleware { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheMiddleware<S> {
+    inner: S,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires > now => Some(entry.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: String, entry: CachedResponse) {
+        self.entries.lock().unwrap().put(key, entry);
+    }
+}
+
+impl CacheRule {
+    pub fn try_new(config: &CacheConfig) -> anyhow::Result<Self> {
+        let vary = config
+            .vary
+            .iter()
+            .map(|h| HeaderName::try_from(h.as_str()))
+            .collect::<Result<_, _>>()
+            .context("cache: invalid vary header")?;
+        Ok(Self {
+            ttl: Duration::from_secs(config.ttl),
+            vary,
+        })
+    }
+
+    /// Key of a request: method, path and query, the values of the `vary`
+    /// headers and the authenticated user, one per line.
+    fn key(&self, request: &Request) -> String {
+        let uri = request.uri();
+        let mut key = format!(
+            "{} {}",
+            request.method(),
+            uri.path_and_query().map_or(uri.path(), |p| p.as_str())
+        );
+        for name in &self.vary {
+            key.push('\n');
+            for value in request.headers().get_all(name) {
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        if let Some(identity) = request.extensions().get::<Identity>() {
+            key.push_str(&format!("\n{}:{}", identity.scheme, identity.subject));
+        }
+        key
+    }
+
+    /// How long a response may be cached, `None` if it must not be.
+    fn ttl(&self, response: &Response) -> Option<Duration> {
+        let status = response.status().as_u16();
+        if ![200, 203, 204, 301, 404, 410].contains(&status) {
+            return None;
+        }
+        let headers = response.headers();
+        // the cookie of one client must never be replayed to another
+        if headers.contains_key(SET_COOKIE) {
+            return None;
+        }
+        // the key only tells apart the `vary` headers of the route
+        for value in headers.get_all(VARY) {
+            let value = value.to_str().ok()?;
+            let varied = value.split(',').map(str::trim).filter(|v| !v.is_empty());
+            for name in varied {
+                if !self
+                    .vary
+                    .iter()
+                    .any(|h| h.as_str().eq_ignore_ascii_case(name))
+                {
+                    return None;
+                }
+            }
+        }
+
+        let mut max_age = None;
+        let mut s_maxage = None;
+        for value in headers.get_all(CACHE_CONTROL) {
+            for directive in value.to_str().ok()?.split(',') {
+                let directive = directive.trim().to_ascii_lowercase();
+                match directive.split_once('=') {
+                    Some(("max-age", v)) => max_age = v.trim_matches('"').parse().ok(),
+                    Some(("s-maxage", v)) => s_maxage = v.trim_matches('"').parse().ok(),
+                    _ if ["no-store", "no-cache", "private"].contains(&directive.as_str()) => {
+                        return None
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // a shared cache uses `s-maxage` over `max-age`
+        let ttl = s_maxage.or(max_age).map_or(self.ttl, Duration::from_secs);
+        (!ttl.is_zero()).then_some(ttl)
+    }
+}
+
+impl CachedResponse {
+    fn to_response(&self, now: Instant) -> Response {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        let age = now.saturating_duration_since(self.stored).as_secs();
+        response.headers_mut().insert(AGE, HeaderValue::from(age));
+        response
+            .headers_mut()
+            .insert(CACHE_STATUS_HEADER, HeaderValue::from_static("hit"));
+        response
+    }
+}
+
+impl<S> Service<Request> for CacheMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let method = request.method();
+        let target = request.extensions().get::<Arc<RequestTarget>>().cloned();
+        let Some(target) = target.filter(|_| method == Method::GET || method == Method::HEAD)
+        else {
+            return Box::pin(self.inner.call(request));
+        };
+        let router = &target.version.router;
+        let path = router.rewrite(&target.path);
+        let path = path.as_deref().unwrap_or(&target.path);
+        let Some(rule) = router.cache(method, path).cloned() else {
+            return Box::pin(self.inner.call(request));
+        };
+        // the response may depend on credentials the key knows nothing of,
+        // unless they were checked by the auth of the route
+        if request.headers().contains_key(AUTHORIZATION)
+            && request.extensions().get::<Identity>().is_none()
+        {
+            return Box::pin(self.inner.call(request));
+        }
+
+        let key = rule.key(&request);
+        let now = Instant::now();
+        if let Some(entry) = target.version.cache.get(&key, now) {
+            let response = entry.to_response(now);
+            return Box::pin(async move { Ok(response) });
+        }
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let response = future.await?;
+            let size = response.body().size_hint().exact();
+            let Some(ttl) = rule
+                .ttl(&response)
+                .filter(|_| size <= Some(MAX_CACHED_BODY))
+            else {
+                return Ok(response);
+            };
+            let (mut parts, body) = response.into_parts();
+            let Ok(body) = to_bytes(body, MAX_CACHED_BODY as usize).await else {
+                // the body failed after the handler returned
+                parts.status = StatusCode::BAD_GATEWAY;
+                return Ok(Response::from_parts(parts, Body::empty()));
+            };
+            let now = Instant::now();
+            let entry = CachedResponse {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                stored: now,
+                expires: now + ttl,
+            };
+            target.version.cache.put(key, entry);
+            parts
+                .headers
+                .insert(CACHE_STATUS_HEADER, HeaderValue::from_static("miss"));
+            Ok(Response::from_parts(parts, Body::from(body)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(yaml: &str) -> CacheRule {
+        let config: CacheConfig = serde_yaml::from_str(yaml).unwrap();
+        CacheRule::try_new(&config).unwrap()
+    }
+
+    fn response(status: u16, headers: &[(HeaderName, &str)]) -> Response {
+        let mut builder = Response::builder().status(status);
+        for (k, v) in headers {
+            builder = builder.header(k, *v);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn cache_rule_should_respect_cache_control() {
+        let rule = rule("ttl: 60\nvary: [accept-language]");
+        let ttl = |status, headers: &[(HeaderName, &str)]| rule.ttl(&response(status, headers));
+        assert_eq!(ttl(200, &[]), Some(Duration::from_secs(60)));
+        assert_eq!(ttl(500, &[]), None);
+        assert_eq!(
+            ttl(200, &[(CACHE_CONTROL, "public, max-age=10")]),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            ttl(200, &[(CACHE_CONTROL, "max-age=10, s-maxage=30")]),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(ttl(200, &[(CACHE_CONTROL, "no-store")]), None);
+        assert_eq!(ttl(200, &[(CACHE_CONTROL, "Private")]), None);
+        assert_eq!(ttl(200, &[(CACHE_CONTROL, "max-age=0")]), None);
+        assert!(ttl(200, &[(VARY, "Accept-Language")]).is_some());
+        assert_eq!(ttl(200, &[(VARY, "cookie")]), None);
+        assert_eq!(ttl(200, &[(VARY, "*")]), None);
+        assert_eq!(ttl(200, &[(SET_COOKIE, "session=1")]), None);
+    }
+
+    #[test]
+    fn cache_key_should_include_vary_and_identity() {
+        let rule = rule("ttl: 60\nvary: [accept-language]");
+        let request = |lang: &str| {
+            Request::builder()
+                .uri("/api/users?page=2")
+                .header("accept-language", lang)
+                .header("cookie", "a=1")
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(rule.key(&request("en")), "GET /api/users?page=2\nen");
+        assert_ne!(rule.key(&request("en")), rule.key(&request("de")));
+
+        let mut req = request("en");
+        req.extensions_mut().insert(Identity {
+            scheme: "basic",
+            subject: "alice".to_string(),
+            claims: Default::default(),
+        });
+        assert_eq!(rule.key(&req), "GET /api/users?page=2\nen\nbasic:alice");
+    }
+
+    #[test]
+    fn response_cache_should_expire_and_evict() {
+        let cache = ResponseCache::new(2);
+        let now = Instant::now();
+        let entry = |ttl| CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"hi"),
+            stored: now,
+            expires: now + Duration::from_secs(ttl),
+        };
+        cache.put("a".to_string(), entry(10));
+        cache.put("b".to_string(), entry(10));
+        assert!(cache.get("a", now).is_some());
+        // `b` is the least recently used
+        cache.put("c".to_string(), entry(10));
+        assert!(cache.get("b", now).is_none());
+        assert_eq!(cache.len(), 2);
+
+        let later = now + Duration::from_secs(10);
+        assert!(cache.get("a", later).is_none());
+        let response = cache.get("c", now + Duration::from_secs(3)).unwrap();
+        let response = response.to_response(now + Duration::from_secs(3));
+        assert_eq!(response.headers()[AGE], "3");
+        assert_eq!(response.headers()[CACHE_STATUS_HEADER], "hit");
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::{bail, Context as _};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+            ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    response::Response,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use crate::{CorsConfig, RequestTarget};
+
+/// A `cors` block of the config, checked and ready to answer requests.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// lowercase origins without trailing `/`, `None` allows any origin
+    origins: Option<Vec<String>>,
+    /// `None` allows the methods of the route
+    methods: Option<Vec<Method>>,
+    /// `None` allows any request header
+    headers: Option<Vec<HeaderName>>,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+/// Answers preflight requests and adds the cors headers to the responses of
+/// cross-origin requests, with the policy of the matched route or tenant.
+/// Needs the `RequestTarget` of the request.
+#[derive(Clone)]
+pub struct CorsLayer;
+
+impl<S> Layer<S> for CorsLayer {
+    type Service = CorsMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CorsMiddleware { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct CorsMiddleware<S> {
+    inner: S,
+}
+
+impl CorsPolicy {
+    pub fn try_new(config: &CorsConfig) -> anyhow::Result<Self> {
+        let any_origin = config.origins.iter().any(|o| o == "*");
+        if any_origin && config.credentials {
+            bail!("cors: credentials can not be allowed for origin `*`, list the origins");
+        }
+        let origins = (!any_origin).then(|| {
+            config
+                .origins
+                .iter()
+                .map(|o| o.trim_end_matches('/').to_ascii_lowercase())
+                .collect()
+        });
+        let methods = (!config.methods.is_empty()).then(|| config.methods.clone());
+        let headers = if config.headers.iter().any(|h| h == "*") {
+            None
+        } else {
+            let headers = config
+                .headers
+                .iter()
+                .map(|h| HeaderName::try_from(h.as_str()))
+                .collect::<Result<_, _>>()
+                .context("cors: invalid header")?;
+            Some(headers)
+        };
+        Ok(Self {
+            origins,
+            methods,
+            headers,
+            credentials: config.credentials,
+            max_age: config.max_age,
+        })
+    }
+
+    /// Answer a preflight request from `origin`. `handled` tells if the route
+    /// has a handler for the requested method.
+    pub fn preflight(&self, origin: &HeaderValue, headers: &HeaderMap, handled: bool) -> Response {
+        let mut res = Response::new(Body::empty());
+        // the answer depends on all of these, caches must not mix them up
+        for name in [
+            ORIGIN,
+            ACCESS_CONTROL_REQUEST_METHOD,
+            ACCESS_CONTROL_REQUEST_HEADERS,
+        ] {
+            res.headers_mut().append(VARY, HeaderValue::from_name(name));
+        }
+        match self.preflight_headers(origin, headers, handled) {
+            Some(allowed) => {
+                *res.status_mut() = StatusCode::NO_CONTENT;
+                res.headers_mut().extend(allowed);
+            }
+            None => *res.status_mut() = StatusCode::FORBIDDEN,
+        }
+        res
+    }
+
+    /// Add the cors headers to the response of an actual request from
+    /// `origin`. A disallowed origin gets none, so the browser blocks it.
+    pub fn apply(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        if self.origins.is_some() {
+            headers.append(VARY, HeaderValue::from_name(ORIGIN));
+        }
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return;
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight_headers(
+        &self,
+        origin: &HeaderValue,
+        headers: &HeaderMap,
+        handled: bool,
+    ) -> Option<HeaderMap> {
+        let allow_origin = self.allow_origin(origin)?;
+        let method = headers.get(ACCESS_CONTROL_REQUEST_METHOD)?;
+        let method = Method::from_bytes(method.as_bytes()).ok()?;
+        let allowed = match &self.methods {
+            Some(methods) => methods.contains(&method),
+            // simple methods are allowed anyway, see the fetch standard
+            None => handled || [Method::GET, Method::HEAD, Method::POST].contains(&method),
+        };
+        if !allowed {
+            return None;
+        }
+        let requested = headers
+            .get(ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let requested: Vec<&str> = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+        if let Some(allowed) = &self.headers {
+            let is_allowed = |h: &&str| allowed.iter().any(|a| a.as_str().eq_ignore_ascii_case(h));
+            if !requested.iter().all(is_allowed) {
+                return None;
+            }
+        }
+
+        let mut ret = HeaderMap::new();
+        ret.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        ret.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_str(method.as_str()).ok()?,
+        );
+        if !requested.is_empty() {
+            ret.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_str(&requested.join(", ")).ok()?,
+            );
+        }
+        if self.credentials {
+            ret.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(max_age) = self.max_age {
+            ret.insert(ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+        Some(ret)
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for `origin`, `None` if the
+    /// origin is not allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let Some(origins) = &self.origins else {
+            return Some(HeaderValue::from_static("*"));
+        };
+        let value = origin.to_str().ok()?.to_ascii_lowercase();
+        origins.contains(&value).then(|| origin.clone())
+    }
+}
+
+impl<S> Service<Request> for CorsMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let origin = request.headers().get(ORIGIN).cloned();
+        let target = request.extensions().get::<Arc<RequestTarget>>().cloned();
+        let (Some(origin), Some(target)) = (origin, target) else {
+            return Box::pin(self.inner.call(request));
+        };
+        let router = &target.version.router;
+        let path = router.rewrite(&target.path);
+        let path = path.as_deref().unwrap_or(&target.path);
+
+        let requested = request
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok());
+        match requested {
+            Some(method) if request.method() == Method::OPTIONS => {
+                if let Some(policy) = router.cors(&method, path) {
+                    let handled = router.handles(&method, path);
+                    let res = policy.preflight(&origin, request.headers(), handled);
+                    return Box::pin(async move { Ok(res) });
+                }
+            }
+            _ => {
+                if let Some(policy) = router.cors(request.method(), path).cloned() {
+                    let future = self.inner.call(request);
+                    return Box::pin(async move {
+                        let mut response = future.await?;
+                        policy.apply(&origin, response.headers_mut());
+                        Ok(response)
+                    });
+                }
+            }
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(yaml: &str) -> CorsPolicy {
+        let config: CorsConfig = serde_yaml::from_str(yaml).unwrap();
+        CorsPolicy::try_new(&config).unwrap()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| {
+                (
+                    HeaderName::try_from(*k).unwrap(),
+                    HeaderValue::from_str(v).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cors_preflight_should_work() {
+        let policy = policy(
+            r#"
+origins: ["https://app.example.com"]
+methods: [GET, PUT]
+headers: [content-type]
+credentials: true
+max_age: 600
+"#,
+        );
+        let origin = HeaderValue::from_static("https://app.example.com");
+        let req = headers(&[
+            ("access-control-request-method", "PUT"),
+            ("access-control-request-headers", "Content-Type"),
+        ]);
+        let res = policy.preflight(&origin, &req, false);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let h = res.headers();
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_HEADERS], "Content-Type");
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(h[ACCESS_CONTROL_MAX_AGE], "600");
+
+        let other = HeaderValue::from_static("https://evil.example.com");
+        let res = policy.preflight(&other, &req, false);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let req = headers(&[("access-control-request-method", "DELETE")]);
+        let res = policy.preflight(&origin, &req, true);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = headers(&[
+            ("access-control-request-method", "GET"),
+            ("access-control-request-headers", "x-token"),
+        ]);
+        let res = policy.preflight(&origin, &req, true);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn cors_apply_should_work() {
+        let policy = policy("origins: ['*']\nheaders: ['*']");
+        let origin = HeaderValue::from_static("https://any.example.com");
+        let mut res = HeaderMap::new();
+        policy.apply(&origin, &mut res);
+        assert_eq!(res[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!res.contains_key(VARY));
+
+        // methods of the route by default
+        let req = headers(&[
+            ("access-control-request-method", "DELETE"),
+            ("access-control-request-headers", "x-token"),
+        ]);
+        let res = policy.preflight(&origin, &req, true);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        let res = policy.preflight(&origin, &req, false);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let config: CorsConfig = serde_yaml::from_str("origins: ['*']\ncredentials: true").unwrap();
+        assert!(CorsPolicy::try_new(&config).is_err());
+    }
+}
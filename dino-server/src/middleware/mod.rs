@@ -1,6 +1,19 @@
+mod auth;
+mod cache;
+mod cors;
+mod rate_limit;
 mod server_time;
 
 const SERVER_TIME_HEADER: &str = "x-server-time";
 const REQUEST_ID_HEADER: &str = "x-request-id";
+/// `hit` or `miss` on responses of routes with `cache`
+const CACHE_STATUS_HEADER: &str = "x-dino-cache";
+const RATE_LIMIT_LIMIT_HEADER: &str = "ratelimit-limit";
+const RATE_LIMIT_REMAINING_HEADER: &str = "ratelimit-remaining";
+const RATE_LIMIT_RESET_HEADER: &str = "ratelimit-reset";
 
+pub use auth::{AuthLayer, AuthPolicy, Credentials};
+pub use cache::{CacheLayer, CacheRule, ResponseCache};
+pub use cors::{CorsLayer, CorsPolicy};
+pub use rate_limit::{RateLimitLayer, RateLimiter, RateLimits};
 pub use server_time::ServerTimeLayer;
@@ -0,0 +1,300 @@
+use anyhow::bail;
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+use super::{RATE_LIMIT_LIMIT_HEADER, RATE_LIMIT_REMAINING_HEADER, RATE_LIMIT_RESET_HEADER};
+use crate::{AppError, Identity, RateLimitConfig, RateLimitKey, RequestTarget};
+
+/// buckets are checked for removal every this many requests
+const CLEANUP_INTERVAL: u64 = 1024;
+
+/// Token buckets of one rate limit, by client key. Buckets which are full
+/// again are dropped from time to time, so idle clients take no memory.
+#[derive(Debug)]
+pub struct RateLimiter {
+    key: RateLimitKey,
+    /// tokens per second
+    rate: f64,
+    capacity: f64,
+    buckets: DashMap<String, Bucket>,
+    checks: AtomicU64,
+}
+
+/// The rate limiters of a tenant, shared by all its versions, so a canary
+/// doesn't get buckets of its own and a swap doesn't refill them. A changed
+/// limit starts with full buckets.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    /// by route path and config, dropped with the last version using them
+    limiters: Mutex<HashMap<(String, RateLimitConfig), Weak<RateLimiter>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Outcome of taking a token, for the `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// seconds until the bucket is full again, or until the next token if
+    /// the request was limited
+    pub reset: u64,
+}
+
+/// Answers requests over the rate limits of their route or tenant with 429.
+/// Needs the `RequestTarget` of the request.
+#[derive(Clone)]
+pub struct RateLimitLayer;
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitMiddleware<S> {
+    inner: S,
+}
+
+impl RateLimiter {
+    pub fn try_new(config: &RateLimitConfig) -> anyhow::Result<Self> {
+        if config.requests == 0 || config.period == 0 {
+            bail!("rate limit: requests and period must be positive");
+        }
+        let capacity = config.burst.unwrap_or(config.requests);
+        if capacity == 0 {
+            bail!("rate limit: burst must be positive");
+        }
+        Ok(Self {
+            key: config.key.clone(),
+            rate: config.requests as f64 / config.period as f64,
+            capacity: capacity as f64,
+            buckets: DashMap::new(),
+            checks: AtomicU64::new(0),
+        })
+    }
+
+    /// Key of the bucket of a request.
+    pub fn key(&self, request: &Request, target: &RequestTarget) -> String {
+        let key = match &self.key {
+            RateLimitKey::Ip => None,
+            RateLimitKey::Header(name) => request
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| format!("header:{v}")),
+            RateLimitKey::Subject => request
+                .extensions()
+                .get::<Identity>()
+                .map(|id| format!("subject:{}:{}", id.scheme, id.subject)),
+        };
+        key.unwrap_or_else(|| format!("ip:{}", target.client))
+    }
+
+    /// Take a token from the bucket of `key`.
+    pub fn check(&self, key: &str, now: Instant) -> RateLimitStatus {
+        let checks = self.checks.fetch_add(1, Ordering::Relaxed);
+        if checks % CLEANUP_INTERVAL == CLEANUP_INTERVAL - 1 {
+            self.buckets
+                .retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+        }
+
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refill(&bucket, now);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = if allowed {
+            (self.capacity - bucket.tokens) / self.rate
+        } else {
+            (1.0 - bucket.tokens) / self.rate
+        };
+        RateLimitStatus {
+            allowed,
+            limit: self.capacity as u64,
+            remaining: bucket.tokens as u64,
+            reset: reset.ceil() as u64,
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity)
+    }
+}
+
+impl RateLimits {
+    /// The limiter of `config` on the route at `path`, an empty path for
+    /// the limit of the tenant.
+    pub fn get(&self, path: &str, config: &RateLimitConfig) -> anyhow::Result<Arc<RateLimiter>> {
+        let mut limiters = self.limiters.lock().unwrap();
+        let key = (path.to_string(), config.clone());
+        if let Some(limiter) = limiters.get(&key).and_then(Weak::upgrade) {
+            return Ok(limiter);
+        }
+        limiters.retain(|_, limiter| limiter.strong_count() > 0);
+        let limiter = Arc::new(RateLimiter::try_new(config)?);
+        limiters.insert(key, Arc::downgrade(&limiter));
+        Ok(limiter)
+    }
+}
+
+impl RateLimitStatus {
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.reset)
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(RATE_LIMIT_LIMIT_HEADER, HeaderValue::from(self.limit));
+        headers.insert(
+            RATE_LIMIT_REMAINING_HEADER,
+            HeaderValue::from(self.remaining),
+        );
+        headers.insert(RATE_LIMIT_RESET_HEADER, HeaderValue::from(self.reset));
+    }
+}
+
+impl<S> Service<Request> for RateLimitMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let Some(target) = request.extensions().get::<Arc<RequestTarget>>().cloned() else {
+            return Box::pin(self.inner.call(request));
+        };
+        let router = &target.version.router;
+        let path = router.rewrite(&target.path);
+        let path = path.as_deref().unwrap_or(&target.path);
+
+        // the route's limit first, a request it rejects does not count
+        // against the tenant's
+        let now = Instant::now();
+        let mut status: Option<RateLimitStatus> = None;
+        for limiter in router.rate_limits(request.method(), path) {
+            let current = limiter.check(&limiter.key(&request, &target), now);
+            // the headers show the limit closest to be hit
+            if !current.allowed || status.is_none_or(|s| current.remaining < s.remaining) {
+                status = Some(current);
+            }
+            if !current.allowed {
+                break;
+            }
+        }
+        let Some(status) = status else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        if !status.allowed {
+            let mut response = AppError::RateLimited(status.retry_after()).into_response();
+            status.apply(response.headers_mut());
+            return Box::pin(async move { Ok(response) });
+        }
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            status.apply(response.headers_mut());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(yaml: &str) -> RateLimiter {
+        let config: RateLimitConfig = serde_yaml::from_str(yaml).unwrap();
+        RateLimiter::try_new(&config).unwrap()
+    }
+
+    #[test]
+    fn rate_limiter_should_refill() {
+        let limiter = limiter("requests: 2\nperiod: 10");
+        let now = Instant::now();
+        let status = limiter.check("a", now);
+        assert!(status.allowed);
+        assert_eq!((status.limit, status.remaining, status.reset), (2, 1, 5));
+        assert!(limiter.check("a", now).allowed);
+
+        let status = limiter.check("a", now);
+        assert!(!status.allowed);
+        assert_eq!(status.remaining, 0);
+        assert_eq!(status.retry_after(), Duration::from_secs(5));
+        // buckets are per key
+        assert!(limiter.check("b", now).allowed);
+
+        let later = now + Duration::from_secs(5);
+        assert!(limiter.check("a", later).allowed);
+        assert!(!limiter.check("a", later).allowed);
+    }
+
+    #[test]
+    fn rate_limiter_should_allow_bursts() {
+        let limiter = limiter("requests: 1\nburst: 3\nkey: 'header:x-api-key'");
+        assert_eq!(limiter.key, RateLimitKey::Header("x-api-key".to_string()));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check("a", now).allowed);
+        }
+        assert!(!limiter.check("a", now).allowed);
+
+        let config: Result<RateLimitConfig, _> = serde_yaml::from_str("requests: 1\nkey: user");
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn rate_limits_should_share_limiters() {
+        let limits = RateLimits::default();
+        let config: RateLimitConfig = serde_yaml::from_str("requests: 1").unwrap();
+        let limiter = limits.get("/api", &config).unwrap();
+        assert!(Arc::ptr_eq(&limiter, &limits.get("/api", &config).unwrap()));
+        assert!(!Arc::ptr_eq(&limiter, &limits.get("", &config).unwrap()));
+        let other: RateLimitConfig = serde_yaml::from_str("requests: 2").unwrap();
+        assert!(!Arc::ptr_eq(&limiter, &limits.get("/api", &other).unwrap()));
+
+        // a limiter no version uses anymore starts over
+        assert!(limiter.check("a", Instant::now()).allowed);
+        drop(limiter);
+        let limiter = limits.get("/api", &config).unwrap();
+        assert!(limiter.check("a", Instant::now()).allowed);
+    }
+}
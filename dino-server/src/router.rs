@@ -1,131 +1,572 @@
-use arc_swap::ArcSwap;
-use axum::http::Method;
-use matchit::{Match, Router};
-use std::{ops::Deref, sync::Arc};
+use std::{collections::HashMap, fs, sync::Arc, time::Duration};
 
-use crate::{AppError, ProjectRoutes};
+use anyhow::{anyhow, bail, Context};
+use axum::http::{Method, StatusCode};
+use indexmap::IndexMap;
+use jsonschema::JSONSchema;
+use matchit::{Params, Router};
+use regex::Regex;
 
-#[derive(Clone)]
-pub struct SwappableAppRouter {
-    pub inner: Arc<ArcSwap<AppRouterInner>>,
-}
+use crate::{
+    config_error::suggest,
+    middleware::{AuthPolicy, CacheRule, CorsPolicy, RateLimiter, RateLimits},
+    AppError, Param, ProjectConfig, RouteMethods,
+};
 
-pub struct AppRouterInner {
-    pub code: String,
-    pub routes: Router<MethodRoute>,
+pub struct AppRouter {
+    routes: Router<MethodRoute>,
+    redirects: Router<(StatusCode, PathTemplate)>,
+    rewrites: Router<PathTemplate>,
+    /// handles requests which match no route
+    fallback: Option<RouteEntry>,
+    /// handles requests which match a route, but none of its methods
+    method_not_allowed: Option<RouteEntry>,
+    /// cors policy of the routes without their own
+    cors: Option<Arc<CorsPolicy>>,
+    /// rate limit of all requests to the tenant
+    rate_limit: Option<Arc<RateLimiter>>,
 }
 
-#[derive(Clone)]
-pub struct AppRouter(Arc<AppRouterInner>);
-
 #[derive(Debug, Default, Clone)]
 pub struct MethodRoute {
-    get: Option<String>, // handler name in js code
-    head: Option<String>,
-    delete: Option<String>,
-    options: Option<String>,
-    patch: Option<String>,
-    post: Option<String>,
-    put: Option<String>,
-    trace: Option<String>,
-    connect: Option<String>,
-}
-
-impl SwappableAppRouter {
-    pub fn try_new(code: impl Into<String>, routes: ProjectRoutes) -> anyhow::Result<Self> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
-        Ok(Self {
-            inner: Arc::new(ArcSwap::from_pointee(inner)),
-        })
-    }
+    methods: IndexMap<Method, RouteEntry>,
+    /// handles all methods without a dedicated entry
+    any: Option<RouteEntry>,
+    /// declared types of the params of the path
+    types: HashMap<String, ParamType>,
+}
 
-    pub fn swap(&self, code: impl Into<String>, routes: ProjectRoutes) -> anyhow::Result<()> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
-        self.inner.store(Arc::new(inner));
-        Ok(())
-    }
+/// Type of a route param, declared as `:id<int>`, `:id<float>`,
+/// `:id<uuid>`, or a regex like `:slug<regex([a-z0-9-]+)>`.
+#[derive(Debug, Clone)]
+enum ParamType {
+    Int,
+    Float,
+    Uuid,
+    Regex(Regex),
+}
 
-    pub fn load(&self) -> AppRouter {
-        AppRouter(self.inner.load_full())
+#[derive(Debug, Clone)]
+pub struct RouteEntry {
+    /// handler name in js code
+    pub handler: String,
+    /// worker pool running the handler, `None` for the default pool
+    pub pool: Option<String>,
+    /// handler timeout, `None` for the timeout of the pool
+    pub timeout: Option<Duration>,
+    /// js functions run in order before the handler
+    pub middleware: Vec<String>,
+    pub cors: Option<Arc<CorsPolicy>>,
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    pub auth: Option<Arc<AuthPolicy>>,
+    pub cache: Option<CacheRule>,
+    /// max bytes of the request body, `None` for the limit of the tenant
+    pub max_body_size: Option<usize>,
+    /// accepted content types of a non-empty body, any if empty
+    pub content_types: Vec<String>,
+    /// schema the request body is validated against before the handler runs
+    pub body_schema: Option<Arc<JSONSchema>>,
+}
+
+/// Target of a redirect or rewrite, with the params of the matched path
+/// substituted.
+#[derive(Debug, Clone)]
+struct PathTemplate(Vec<Segment>);
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+#[derive(Debug)]
+pub struct RouteMatch<'m> {
+    pub value: &'m RouteEntry,
+    pub params: HashMap<String, Param>,
+    /// methods allowed by the matched path, set when the request is
+    /// handled by `method_not_allowed`
+    pub allow: Vec<Method>,
+}
+
+impl AppRouter {
+    pub fn try_new(config: &ProjectConfig) -> anyhow::Result<Self> {
+        Self::with_rate_limits(config, &RateLimits::default())
     }
 
-    fn get_router(routes: ProjectRoutes) -> anyhow::Result<Router<MethodRoute>> {
+    /// Like `try_new`, with the rate limiters taken from `limits`, so the
+    /// versions of a tenant share them.
+    pub fn with_rate_limits(config: &ProjectConfig, limits: &RateLimits) -> anyhow::Result<Self> {
         let mut router = Router::new();
-        for (path, methods) in routes {
-            let mut method_route = MethodRoute::default();
-            for method in methods {
-                match method.method {
-                    Method::GET => method_route.get = Some(method.handler),
-                    Method::HEAD => method_route.head = Some(method.handler),
-                    Method::DELETE => method_route.delete = Some(method.handler),
-                    Method::OPTIONS => method_route.options = Some(method.handler),
-                    Method::PATCH => method_route.patch = Some(method.handler),
-                    Method::POST => method_route.post = Some(method.handler),
-                    Method::PUT => method_route.put = Some(method.handler),
-                    Method::TRACE => method_route.trace = Some(method.handler),
-                    Method::CONNECT => method_route.connect = Some(method.handler),
-                    v => unreachable!("unsupported method {v}"),
+        // routes often share a schema, compile it once
+        let mut schemas: HashMap<String, Arc<JSONSchema>> = HashMap::new();
+        for (path, routes) in config.flat_routes()? {
+            let (path, types) = parse_path(&path)?;
+            let mut method_route = MethodRoute {
+                types,
+                ..Default::default()
+            };
+            for route in routes {
+                let cors = match &route.cors {
+                    Some(cors) => Some(Arc::new(
+                        CorsPolicy::try_new(cors).with_context(|| format!("route {path}"))?,
+                    )),
+                    None => None,
+                };
+                let rate_limit = match &route.rate_limit {
+                    Some(limit) => Some(
+                        limits
+                            .get(&path, limit)
+                            .with_context(|| format!("route {path}"))?,
+                    ),
+                    None => None,
+                };
+                let auth = match &route.auth {
+                    Some(auth) => Some(Arc::new(
+                        AuthPolicy::try_new(auth).with_context(|| format!("route {path}"))?,
+                    )),
+                    None => None,
+                };
+                let cache = match &route.cache {
+                    Some(cache) => {
+                        Some(CacheRule::try_new(cache).with_context(|| format!("route {path}"))?)
+                    }
+                    None => None,
+                };
+                let body_schema = match &route.body_schema {
+                    Some(file) => Some(match schemas.get(file) {
+                        Some(schema) => schema.clone(),
+                        None => {
+                            let schema = Arc::new(
+                                compile_schema(file).with_context(|| format!("route {path}"))?,
+                            );
+                            schemas.insert(file.clone(), schema.clone());
+                            schema
+                        }
+                    }),
+                    None => None,
+                };
+                let entry = RouteEntry {
+                    handler: route.handler.clone(),
+                    pool: route.pool.clone(),
+                    timeout: route.timeout.map(Duration::from_millis),
+                    middleware: route.middleware.clone(),
+                    cors,
+                    rate_limit,
+                    auth,
+                    cache,
+                    max_body_size: route.max_body_size,
+                    content_types: route.content_types.clone(),
+                    body_schema,
+                };
+                match &route.method {
+                    RouteMethods::Any => method_route.any = Some(entry),
+                    RouteMethods::Methods(methods) => {
+                        for method in methods {
+                            method_route.methods.insert(method.clone(), entry.clone());
+                        }
+                    }
                 }
             }
             router.insert(path, method_route)?;
         }
-        Ok(router)
+
+        let mut redirects = Router::new();
+        for redirect in &config.redirects {
+            let status = StatusCode::from_u16(redirect.status)
+                .ok()
+                .filter(|s| s.is_redirection() && s.as_u16() != 300 && s.as_u16() != 304)
+                .with_context(|| {
+                    format!(
+                        "invalid redirect status {} for {}",
+                        redirect.status, redirect.from
+                    )
+                })?;
+            let to = PathTemplate::parse(&redirect.from, &redirect.to)?;
+            redirects
+                .insert(&redirect.from, (status, to))
+                .with_context(|| format!("invalid redirect {}", redirect.from))?;
+        }
+        let mut rewrites = Router::new();
+        for rewrite in &config.rewrites {
+            let to = PathTemplate::parse(&rewrite.from, &rewrite.to)?;
+            rewrites
+                .insert(&rewrite.from, to)
+                .with_context(|| format!("invalid rewrite {}", rewrite.from))?;
+        }
+
+        let entry = |handler: &Option<String>| {
+            handler.as_ref().map(|handler| RouteEntry {
+                handler: handler.clone(),
+                pool: None,
+                timeout: None,
+                middleware: vec![],
+                cors: None,
+                rate_limit: None,
+                auth: None,
+                cache: None,
+                max_body_size: None,
+                content_types: vec![],
+                body_schema: None,
+            })
+        };
+        let cors = match &config.cors {
+            Some(cors) => Some(Arc::new(CorsPolicy::try_new(cors)?)),
+            None => None,
+        };
+        let rate_limit = match &config.rate_limit {
+            Some(limit) => Some(limits.get("", limit)?),
+            None => None,
+        };
+        Ok(Self {
+            routes: router,
+            redirects,
+            rewrites,
+            fallback: entry(&config.fallback),
+            method_not_allowed: entry(&config.method_not_allowed),
+            cors,
+            rate_limit,
+        })
     }
-}
 
-impl AppRouter {
-    pub fn match_it<'m, 'p>(
-        &'m self,
-        method: Method,
-        path: &'p str,
-    ) -> Result<Match<&str>, AppError>
-    where
-        'p: 'm,
-    {
+    pub fn match_it(&self, method: Method, path: &str) -> Result<RouteMatch<'_>, AppError> {
         let Ok(ret) = self.routes.at(path) else {
-            return Err(AppError::RoutePathNotFound(path.to_string()));
+            return match &self.fallback {
+                Some(fallback) => Ok(RouteMatch {
+                    value: fallback,
+                    params: HashMap::new(),
+                    allow: vec![],
+                }),
+                None => Err(AppError::RoutePathNotFound(path.to_string())),
+            };
         };
+        let params = ret.value.params(&ret.params)?;
 
-        let s = match method {
-            Method::GET => ret.value.get.as_deref(),
-            Method::HEAD => ret.value.head.as_deref(),
-            Method::DELETE => ret.value.delete.as_deref(),
-            Method::OPTIONS => ret.value.options.as_deref(),
-            Method::PATCH => ret.value.patch.as_deref(),
-            Method::POST => ret.value.post.as_deref(),
-            Method::PUT => ret.value.put.as_deref(),
-            Method::TRACE => ret.value.trace.as_deref(),
-            Method::CONNECT => ret.value.connect.as_deref(),
-            _ => unreachable!(),
-        }
-        .ok_or_else(|| AppError::RouteMethodNotAllowed(method))?;
-        Ok(Match {
-            value: s,
-            params: ret.params,
-        })
+        if let Some(value) = ret.value.get(&method) {
+            return Ok(RouteMatch {
+                value,
+                params,
+                allow: vec![],
+            });
+        }
+        let allow = ret.value.allow();
+        match &self.method_not_allowed {
+            Some(value) => Ok(RouteMatch {
+                value,
+                params,
+                allow,
+            }),
+            None => Err(AppError::RouteMethodNotAllowed(method, allow)),
+        }
+    }
+
+    /// Status and location of the redirect for `path`, if any. The query
+    /// string is kept unless the target has its own.
+    pub fn redirect(&self, path: &str, query: Option<&str>) -> Option<(StatusCode, String)> {
+        let ret = self.redirects.at(path).ok()?;
+        let (status, to) = ret.value;
+        let mut location = to.render(&ret.params);
+        if let Some(query) = query.filter(|_| !location.contains('?')) {
+            location = format!("{location}?{query}");
+        }
+        Some((*status, location))
+    }
+
+    /// The path `path` is rewritten to, if any.
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        let ret = self.rewrites.at(path).ok()?;
+        Some(ret.value.render(&ret.params))
+    }
+
+    /// Methods allowed by `path`, if an `OPTIONS` request to it should be
+    /// answered without calling js, i.e. the route has no handler for it.
+    pub fn auto_options(&self, path: &str) -> Option<Vec<Method>> {
+        let ret = self.routes.at(path).ok()?;
+        match ret.value.get(&Method::OPTIONS) {
+            Some(_) => None,
+            None => Some(ret.value.allow()),
+        }
+    }
+
+    /// The cors policy of the route handling `method` at `path`, or of the
+    /// tenant.
+    pub fn cors(&self, method: &Method, path: &str) -> Option<&Arc<CorsPolicy>> {
+        self.routes
+            .at(path)
+            .ok()
+            .and_then(|ret| ret.value.get(method))
+            .and_then(|entry| entry.cors.as_ref())
+            .or(self.cors.as_ref())
+    }
+
+    /// The rate limits of a request: of the route handling `method` at
+    /// `path`, then of the tenant.
+    pub fn rate_limits(&self, method: &Method, path: &str) -> impl Iterator<Item = &RateLimiter> {
+        let route = self
+            .routes
+            .at(path)
+            .ok()
+            .and_then(|ret| ret.value.get(method))
+            .and_then(|entry| entry.rate_limit.as_deref());
+        route.into_iter().chain(self.rate_limit.as_deref())
+    }
+
+    /// The auth policy of the route handling `method` at `path`, if any.
+    pub fn auth(&self, method: &Method, path: &str) -> Option<&AuthPolicy> {
+        self.routes
+            .at(path)
+            .ok()
+            .and_then(|ret| ret.value.get(method))
+            .and_then(|entry| entry.auth.as_deref())
+    }
+
+    /// The cache rule of the route handling `method` at `path`, if any.
+    pub fn cache(&self, method: &Method, path: &str) -> Option<&CacheRule> {
+        self.routes
+            .at(path)
+            .ok()
+            .and_then(|ret| ret.value.get(method))
+            .and_then(|entry| entry.cache.as_ref())
+    }
+
+    /// Whether a route has a handler for `method` at `path`.
+    pub fn handles(&self, method: &Method, path: &str) -> bool {
+        self.routes
+            .at(path)
+            .is_ok_and(|ret| ret.value.get(method).is_some())
     }
 }
 
-impl Deref for AppRouter {
-    type Target = AppRouterInner;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl MethodRoute {
+    /// Params of a matched path, converted to their declared types.
+    fn params(&self, params: &Params) -> Result<HashMap<String, Param>, AppError> {
+        params
+            .iter()
+            .map(|(k, v)| {
+                let value = match self.types.get(k) {
+                    Some(ty) => ty.convert(v).ok_or_else(|| {
+                        AppError::InvalidParam(format!("{k} must be {}, got {v}", ty.describe()))
+                    })?,
+                    None => Param::Str(v.to_string()),
+                };
+                Ok((k.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// The entry handling `method`, `HEAD` is handled by `GET` if the route
+    /// has no dedicated entry for it.
+    fn get(&self, method: &Method) -> Option<&RouteEntry> {
+        self.methods
+            .get(method)
+            .or_else(|| match *method {
+                Method::HEAD => self.methods.get(&Method::GET),
+                _ => None,
+            })
+            .or(self.any.as_ref())
+    }
+
+    fn allow(&self) -> Vec<Method> {
+        let mut allow: Vec<Method> = self.methods.keys().cloned().collect();
+        if self.methods.contains_key(&Method::GET) && !allow.contains(&Method::HEAD) {
+            allow.push(Method::HEAD);
+        }
+        if !allow.contains(&Method::OPTIONS) {
+            allow.push(Method::OPTIONS);
+        }
+        allow
     }
 }
 
-impl AppRouterInner {
-    pub fn new(code: impl Into<String>, router: Router<MethodRoute>) -> Self {
-        Self {
-            code: code.into(),
-            routes: router,
+impl PathTemplate {
+    /// Parse `to`, every `:name` or `*name` starting a segment of its path
+    /// must be a param of `from`. The scheme and authority of an absolute
+    /// url have no params, e.g. the port of `http://host:8080/`.
+    fn parse(from: &str, to: &str) -> anyhow::Result<Self> {
+        let params = param_names(from);
+        let path_start = match to.find("://") {
+            Some(pos) => to[pos + 3..].find('/').map_or(to.len(), |p| pos + 3 + p),
+            None => 0,
+        };
+        let mut segments = vec![];
+        let mut literal = to[..path_start].to_string();
+        for (i, part) in to[path_start..].split('/').enumerate() {
+            if i > 0 {
+                literal.push('/');
+            }
+            let Some(after) = part.strip_prefix([':', '*']) else {
+                literal.push_str(part);
+                continue;
+            };
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            if len == 0 {
+                literal.push_str(part);
+                continue;
+            }
+            let name = &after[..len];
+            if !params.contains(&name) {
+                bail!("param `{name}` of {to} is not defined by {from}");
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Param(name.to_string()));
+            literal.push_str(&after[len..]);
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self(segments))
+    }
+
+    fn render(&self, params: &Params) -> String {
+        let rendered: String = self
+            .0
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(s) => s.as_str(),
+                Segment::Param(name) => params.get(name).unwrap_or_default(),
+            })
+            .collect();
+        // a param must not turn a local path into `//host`, which browsers
+        // take as another host
+        let local = matches!(self.0.first(), Some(Segment::Literal(s)) if s.starts_with('/') && !s.starts_with("//"));
+        if local && rendered.starts_with(['/', '\\']) {
+            return format!("/{}", rendered.trim_start_matches(['/', '\\']));
+        }
+        rendered
+    }
+}
+
+impl ParamType {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        const TYPES: [&str; 3] = ["int", "float", "uuid"];
+        Ok(match s {
+            "int" => ParamType::Int,
+            "float" => ParamType::Float,
+            "uuid" => ParamType::Uuid,
+            _ => {
+                // anything else is a typo, not a pattern matching itself
+                let Some(re) = s.strip_prefix("regex(").and_then(|s| s.strip_suffix(')')) else {
+                    match suggest(s, TYPES) {
+                        Some(ty) => bail!("unknown type `{s}`, did you mean `{ty}`?"),
+                        None => {
+                            bail!("unknown type `{s}`, expected int, float, uuid or regex(...)")
+                        }
+                    }
+                };
+                ParamType::Regex(Regex::new(&format!("^(?:{re})$"))?)
+            }
+        })
+    }
+
+    fn convert(&self, v: &str) -> Option<Param> {
+        match self {
+            ParamType::Int => v.parse().ok().map(Param::Int),
+            ParamType::Float => v
+                .parse()
+                .ok()
+                .filter(|f: &f64| f.is_finite())
+                .map(Param::Float),
+            ParamType::Uuid => is_uuid(v).then(|| Param::Str(v.to_string())),
+            ParamType::Regex(re) => re.is_match(v).then(|| Param::Str(v.to_string())),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ParamType::Int => "an integer".to_string(),
+            ParamType::Float => "a number".to_string(),
+            ParamType::Uuid => "a uuid".to_string(),
+            ParamType::Regex(re) => format!("a match of {}", re.as_str()),
         }
     }
 }
 
+/// `8-4-4-4-12` hex digits, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+fn is_uuid(v: &str) -> bool {
+    let groups: Vec<&str> = v.split('-').collect();
+    groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(g, len)| g.len() == len && g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Remove the param types from a config path, e.g. `/users/:id<int>` is
+/// `/users/:id` for matchit, with `id` an int.
+fn parse_path(path: &str) -> anyhow::Result<(String, HashMap<String, ParamType>)> {
+    let mut stripped = String::with_capacity(path.len());
+    let mut types = HashMap::new();
+    let mut chars = path.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        stripped.push(c);
+        if c != ':' || (i > 0 && !path[..i].ends_with('/')) {
+            continue;
+        }
+        let mut name = String::new();
+        while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        stripped.push_str(&name);
+        if chars.next_if(|(_, c)| *c == '<').is_none() {
+            continue;
+        }
+        // regexes may contain `<` and `>`, e.g. named groups
+        let mut depth = 1;
+        let mut ty = String::new();
+        for (_, c) in chars.by_ref() {
+            match c {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            ty.push(c);
+        }
+        if depth != 0 {
+            bail!("unclosed type of param `{name}` in {path}");
+        }
+        let ty = ParamType::parse(&ty)
+            .with_context(|| format!("invalid type of param `{name}` in {path}"))?;
+        types.insert(name, ty);
+    }
+    Ok((stripped, types))
+}
+
+/// Compile the JSON Schema in `file`.
+fn compile_schema(file: &str) -> anyhow::Result<JSONSchema> {
+    let content =
+        fs::read_to_string(file).with_context(|| format!("failed to read schema {file}"))?;
+    let schema: serde_json::Value =
+        serde_json::from_str(&content).with_context(|| format!("invalid schema {file}"))?;
+    JSONSchema::compile(&schema).map_err(|e| anyhow!("invalid schema {file}: {e}"))
+}
+
+/// Names of the params of a matchit path, e.g. `id` and `rest` for
+/// `/users/:id/*rest`.
+fn param_names(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter_map(|s| s.strip_prefix(':').or_else(|| s.strip_prefix('*')))
+        .collect()
+}
+
+/// Value of the `Allow` header for the given methods.
+pub fn allow_header(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(|m| m.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[cfg(test)]
 mod tests {
+    use axum::http::{header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderMap, HeaderValue};
+
     use crate::ProjectConfig;
 
     use super::*;
@@ -134,35 +575,254 @@ mod tests {
     fn app_router_match_should_work() {
         let config = include_str!("../fixtures/config.yml");
         let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
-        let router = SwappableAppRouter::try_new("", config.routes).unwrap();
-        let app_router = router.load();
+        let app_router = AppRouter::try_new(&config).unwrap();
         let m = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
-        assert_eq!(m.value, "hello");
-        assert_eq!(m.params.get("id"), Some("1"));
+        assert_eq!(m.value.handler, "hello");
+        assert_eq!(m.value.pool, None);
+        assert_eq!(param(&m, "id"), Some("1"));
 
         let m = app_router.match_it(Method::POST, "/api/goodbye/2").unwrap();
-        assert_eq!(m.value, "hello");
-        assert_eq!(m.params.get("id"), Some("2"));
-        assert_eq!(m.params.get("name"), Some("goodbye"));
+        assert_eq!(m.value.handler, "hello");
+        assert_eq!(param(&m, "id"), Some("2"));
+        assert_eq!(param(&m, "name"), Some("goodbye"));
+
+        assert!(matches!(
+            app_router.match_it(Method::GET, "/not/found"),
+            Err(AppError::RoutePathNotFound(_))
+        ));
+        assert!(matches!(
+            app_router.match_it(Method::PUT, "/api/hello/1"),
+            Err(AppError::RouteMethodNotAllowed(..))
+        ));
     }
 
     #[test]
-    fn app_router_swap_should_work() {
-        let config = include_str!("../fixtures/config.yml");
+    fn app_router_groups_should_work() -> anyhow::Result<()> {
+        let config = ProjectConfig::load("fixtures/config5.yml")?;
+        let app_router = AppRouter::try_new(&config)?;
+        let m = app_router.match_it(Method::GET, "/api/admin/jobs/7")?;
+        assert_eq!(m.value.handler, "job");
+        assert_eq!(m.value.timeout, Some(Duration::from_secs(60)));
+        assert_eq!(param(&m, "id"), Some("7"));
+
+        let m = app_router.match_it(Method::POST, "/api/users/1")?;
+        assert_eq!(m.value.handler, "user");
+        assert_eq!(m.value.timeout, Some(Duration::from_millis(100)));
+        let m = app_router.match_it(Method::GET, "/api/users")?;
+        assert_eq!(m.value.handler, "users");
+        Ok(())
+    }
+
+    #[test]
+    fn app_router_redirects_and_rewrites_should_work() -> anyhow::Result<()> {
+        let config = ProjectConfig::load("fixtures/config6.yml")?;
+        let app_router = AppRouter::try_new(&config)?;
+
+        let (status, location) = app_router.redirect("/old/users/1", None).unwrap();
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(location, "/api/users/1");
+        let (status, location) = app_router
+            .redirect("/docs/guide/intro", Some("lang=en"))
+            .unwrap();
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(location, "https://docs.example.com/guide/intro?lang=en");
+        assert!(app_router.redirect("/api/users/1", None).is_none());
+
+        assert_eq!(
+            app_router.rewrite("/v1/users/1").as_deref(),
+            Some("/api/users/1")
+        );
+        assert!(app_router.rewrite("/api/users/1").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn path_template_should_only_take_params_in_the_path() -> anyhow::Result<()> {
+        let render = |from: &str, to: &str, path: &str| -> anyhow::Result<String> {
+            let template = PathTemplate::parse(from, to)?;
+            let mut router = Router::new();
+            router.insert(from, ())?;
+            Ok(template.render(&router.at(path)?.params))
+        };
+        assert_eq!(
+            render("/a/:id", "http://host:8080/b/:id.json", "/a/1")?,
+            "http://host:8080/b/1.json"
+        );
+        assert_eq!(render("/a/:id", "/b?id=:id", "/a/1")?, "/b?id=:id");
+        // not an open redirect to `//evil.com`
+        assert_eq!(render("/go/*rest", "/*rest", "/go//evil.com")?, "/evil.com");
+        assert_eq!(
+            render("/go/*rest", "/*rest", "/go/\\evil.com")?,
+            "/evil.com"
+        );
+        assert_eq!(
+            render("/cdn/*rest", "//cdn.example.com/*rest", "/cdn/a.js")?,
+            "//cdn.example.com/a.js"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn app_router_invalid_redirects_should_fail() {
+        let config: ProjectConfig = serde_yaml::from_str(
+            r#"
+name: invalid
+redirects:
+  - from: /old/:id
+    to: /new/:name
+routes: {}
+"#,
+        )
+        .unwrap();
+        let err = AppRouter::try_new(&config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "param `name` of /new/:name is not defined by /old/:id"
+        );
+    }
+
+    #[test]
+    fn app_router_typed_params_should_work() -> anyhow::Result<()> {
+        let config = ProjectConfig::load("fixtures/config7.yml")?;
+        let app_router = AppRouter::try_new(&config)?;
+
+        let m = app_router.match_it(Method::GET, "/api/users/42")?;
+        assert_eq!(m.params["id"], Param::Int(42));
+        assert!(matches!(
+            app_router.match_it(Method::GET, "/api/users/abc"),
+            Err(AppError::InvalidParam(_))
+        ));
+
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let m = app_router.match_it(Method::GET, &format!("/api/orders/{id}"))?;
+        assert_eq!(param(&m, "id"), Some(id));
+        assert!(app_router
+            .match_it(Method::GET, "/api/orders/67e55044")
+            .is_err());
+
+        let m = app_router.match_it(Method::GET, "/api/posts/hello-world/1.5")?;
+        assert_eq!(param(&m, "slug"), Some("hello-world"));
+        assert_eq!(m.params["score"], Param::Float(1.5));
+        assert!(app_router
+            .match_it(Method::GET, "/api/posts/Hello/1")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_path_should_strip_types() -> anyhow::Result<()> {
+        let (path, types) = parse_path("/a/:id<int>/b/:name<regex((?P<x>[a-z]+))>/*rest")?;
+        assert_eq!(path, "/a/:id/b/:name/*rest");
+        assert_eq!(types.len(), 2);
+        assert!(parse_path("/a/:id<int").is_err());
+        assert!(parse_path("/a/:id<regex([a-z)>").is_err());
+        let err = parse_path("/a/:id<number>").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "invalid type of param `id` in /a/:id<number>: \
+             unknown type `number`, expected int, float, uuid or regex(...)"
+        );
+        let err = parse_path("/a/:id<itn>").unwrap_err();
+        assert!(format!("{err:#}").ends_with("did you mean `int`?"));
+        Ok(())
+    }
+
+    #[test]
+    fn app_router_methods_should_work() {
+        let config = include_str!("../fixtures/config4.yml");
         let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
-        let router = SwappableAppRouter::try_new("", config.routes).unwrap();
-        let app_router = router.load();
-        let m = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
-        assert_eq!(m.value, "hello");
+        let app_router = AppRouter::try_new(&config).unwrap();
 
-        let new_config = include_str!("../fixtures/config1.yml");
-        let new_config: ProjectConfig = serde_yaml::from_str(new_config).unwrap();
-        router.swap("", new_config.routes).unwrap();
-        let app_router = router.load();
-        let m = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
-        assert_eq!(m.value, "hello1");
+        for method in [Method::GET, Method::POST, Method::HEAD] {
+            let m = app_router.match_it(method, "/api/users/1").unwrap();
+            assert_eq!(m.value.handler, "user");
+        }
+        let m = app_router.match_it(Method::DELETE, "/api/users/1").unwrap();
+        assert_eq!(m.value.handler, "delete_user");
+        let m = app_router
+            .match_it(Method::HEAD, "/api/users/1/avatar")
+            .unwrap();
+        assert_eq!(m.value.handler, "avatar_head");
 
-        let m = app_router.match_it(Method::POST, "/api/goodbye/2").unwrap();
-        assert_eq!(m.value, "handler2");
+        let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+        let m = app_router.match_it(propfind.clone(), "/api/any").unwrap();
+        assert_eq!(m.value.handler, "any");
+        let Err(AppError::RouteMethodNotAllowed(_, allow)) =
+            app_router.match_it(propfind, "/api/users/1")
+        else {
+            panic!("expect method not allowed");
+        };
+        assert_eq!(allow_header(&allow), "GET, POST, DELETE, HEAD, OPTIONS");
+
+        let allow = app_router.auto_options("/api/users/1").unwrap();
+        assert_eq!(allow_header(&allow), "GET, POST, DELETE, HEAD, OPTIONS");
+        assert!(app_router.auto_options("/api/users/1/options").is_none());
+        assert!(app_router.auto_options("/not/found").is_none());
+    }
+
+    #[test]
+    fn app_router_fallback_should_work() {
+        let config = include_str!("../fixtures/config3.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let app_router = AppRouter::try_new(&config).unwrap();
+
+        let m = app_router.match_it(Method::GET, "/app/users/1").unwrap();
+        assert_eq!(m.value.handler, "spa");
+        assert_eq!(param(&m, "rest"), Some("users/1"));
+
+        let m = app_router.match_it(Method::GET, "/not/found").unwrap();
+        assert_eq!(m.value.handler, "not_found");
+        assert!(m.params.is_empty());
+
+        let m = app_router.match_it(Method::DELETE, "/app/users/1").unwrap();
+        assert_eq!(m.value.handler, "method_not_allowed");
+        assert_eq!(param(&m, "rest"), Some("users/1"));
+        assert_eq!(m.allow, [Method::GET, Method::HEAD, Method::OPTIONS]);
+    }
+
+    #[test]
+    fn app_router_cors_rate_limits_and_auth_should_inherit() {
+        let config = include_str!("../fixtures/config8.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let app_router = AppRouter::try_new(&config).unwrap();
+        let allow_origin = |method: Method, path: &str, origin: &'static str| {
+            let mut headers = HeaderMap::new();
+            let policy = app_router.cors(&method, path).unwrap();
+            policy.apply(&HeaderValue::from_static(origin), &mut headers);
+            headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).cloned()
+        };
+
+        let admin = "https://admin.example.com";
+        assert_eq!(
+            allow_origin(Method::GET, "/api/public", admin).unwrap(),
+            "*"
+        );
+        assert_eq!(
+            allow_origin(Method::DELETE, "/api/admin/users", admin).unwrap(),
+            admin
+        );
+        assert!(allow_origin(Method::GET, "/api/hello", admin).is_none());
+        // assets and unknown methods fall back to the policy of the tenant
+        let origin = "https://example.com";
+        assert!(allow_origin(Method::GET, "/index.html", origin).is_some());
+        assert!(allow_origin(Method::PUT, "/api/admin/users", origin).is_some());
+
+        assert!(app_router.handles(&Method::DELETE, "/api/admin/users"));
+
+        let limits = |method, path| app_router.rate_limits(&method, path).count();
+        assert_eq!(limits(Method::GET, "/api/admin/users"), 2);
+        assert_eq!(limits(Method::GET, "/api/hello"), 1);
+        assert!(app_router.auth(&Method::GET, "/api/admin/users").is_some());
+        assert!(app_router.auth(&Method::GET, "/api/hello").is_none());
+        assert!(app_router.cache(&Method::GET, "/api/public").is_some());
+        assert!(app_router.cache(&Method::GET, "/api/hello").is_none());
+        assert!(!app_router.handles(&Method::PUT, "/api/admin/users"));
+    }
+
+    fn param<'a>(m: &'a RouteMatch, name: &str) -> Option<&'a str> {
+        match m.params.get(name) {
+            Some(Param::Str(s)) => Some(s),
+            _ => None,
+        }
     }
 }
@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+/// upper bounds (in milliseconds) of the latency histogram buckets
+const LATENCY_BUCKETS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Metrics of a worker pool, updated by the pool and its worker threads.
+#[derive(Debug)]
+pub struct PoolMetrics {
+    workers: Vec<WorkerMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct WorkerMetrics {
+    queued: AtomicUsize,
+    busy: AtomicBool,
+    served: AtomicU64,
+    // time spent in the `init` hook, in microseconds
+    init_us: AtomicU64,
+    // latencies by handler, only locked by the worker and `stats`, the
+    // workers never wait for each other
+    handlers: Mutex<HashMap<String, Histogram>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    // the last bucket counts everything above the last bound
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    pub size: usize,
+    pub busy: usize,
+    pub idle: usize,
+    pub requests: u64,
+    pub workers: Vec<WorkerStats>,
+    pub handlers: BTreeMap<String, LatencyStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerStats {
+    pub queued: usize,
+    pub busy: bool,
+    pub served: u64,
+    pub init_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub buckets: Vec<LatencyBucket>,
+}
+
+/// Number of requests which took at most `le_ms` milliseconds. The last
+/// bucket has no bound and counts all requests.
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyBucket {
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+impl PoolMetrics {
+    pub fn new(size: usize) -> Self {
+        Self {
+            workers: (0..size).map(|_| WorkerMetrics::default()).collect(),
+        }
+    }
+
+    /// A worker is (re)started, `elapsed` is the time spent in `init`.
+    pub fn initialized(&self, index: usize, elapsed: Option<Duration>) {
+        let us = elapsed.map(|d| d.as_micros() as u64).unwrap_or_default();
+        self.workers[index].init_us.store(us, Ordering::Relaxed);
+    }
+
+    /// A request is sent to the queue of a worker.
+    pub fn queued(&self, index: usize) {
+        self.workers[index].queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A worker takes a request from its queue.
+    pub fn started(&self, index: usize) {
+        let worker = &self.workers[index];
+        worker.queued.fetch_sub(1, Ordering::Relaxed);
+        worker.busy.store(true, Ordering::Relaxed);
+    }
+
+    /// A worker is done with a request.
+    pub fn finished(&self, index: usize, handler: &str, elapsed: Duration) {
+        let worker = &self.workers[index];
+        worker.busy.store(false, Ordering::Relaxed);
+        worker.served.fetch_add(1, Ordering::Relaxed);
+
+        let mut handlers = worker.handlers.lock().unwrap();
+        match handlers.get_mut(handler) {
+            Some(histogram) => histogram.record(elapsed),
+            None => {
+                let mut histogram = Histogram::default();
+                histogram.record(elapsed);
+                handlers.insert(handler.to_string(), histogram);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let workers: Vec<_> = self
+            .workers
+            .iter()
+            .map(|w| WorkerStats {
+                queued: w.queued.load(Ordering::Relaxed),
+                busy: w.busy.load(Ordering::Relaxed),
+                served: w.served.load(Ordering::Relaxed),
+                init_ms: w.init_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            })
+            .collect();
+        let busy = workers.iter().filter(|w| w.busy).count();
+        let mut histograms: BTreeMap<String, Histogram> = BTreeMap::new();
+        for worker in &self.workers {
+            for (name, histogram) in worker.handlers.lock().unwrap().iter() {
+                histograms.entry(name.clone()).or_default().merge(histogram);
+            }
+        }
+        let handlers = histograms
+            .into_iter()
+            .map(|(name, histogram)| (name, histogram.stats()))
+            .collect();
+
+        PoolStats {
+            size: workers.len(),
+            busy,
+            idle: workers.len() - busy,
+            requests: workers.iter().map(|w| w.served).sum(),
+            workers,
+            handlers,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        // compared as durations, whole milliseconds would put 1.9ms in the
+        // 1ms bucket
+        let index = LATENCY_BUCKETS
+            .iter()
+            .position(|le| elapsed <= Duration::from_millis(*le))
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[index] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    fn stats(&self) -> LatencyStats {
+        let mut total = 0;
+        let buckets = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                total += count;
+                LatencyBucket {
+                    le_ms: LATENCY_BUCKETS.get(i).copied(),
+                    count: total,
+                }
+            })
+            .collect();
+        let mean_ms = match self.count {
+            0 => 0.0,
+            n => self.sum.as_secs_f64() * 1000.0 / n as f64,
+        };
+        LatencyStats {
+            count: self.count,
+            mean_ms,
+            buckets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_metrics_should_work() {
+        let metrics = PoolMetrics::new(2);
+        metrics.initialized(0, Some(Duration::from_millis(2)));
+        metrics.initialized(1, None);
+        metrics.queued(0);
+        metrics.queued(0);
+        metrics.started(0);
+        metrics.finished(0, "hello", Duration::from_millis(3));
+        metrics.started(0);
+
+        let stats = metrics.stats();
+        assert_eq!(stats.size, 2);
+        assert_eq!(stats.busy, 1);
+        assert_eq!(stats.idle, 1);
+        assert_eq!(stats.requests, 1);
+        assert_eq!(stats.workers[0].queued, 0);
+        assert!(stats.workers[0].busy);
+        assert_eq!(stats.workers[0].init_ms, 2.0);
+        assert_eq!(stats.workers[1].init_ms, 0.0);
+
+        metrics.finished(0, "hello", Duration::from_secs(10));
+        let stats = metrics.stats();
+        let hello = &stats.handlers["hello"];
+        assert_eq!(hello.count, 2);
+        // 3ms falls in the 5ms bucket, 10s only in the unbounded one
+        assert_eq!(hello.buckets[1].count, 0);
+        assert_eq!(hello.buckets[2].count, 1);
+        assert_eq!(hello.buckets[11].count, 1);
+        assert_eq!(hello.buckets[12].le_ms, None);
+        assert_eq!(hello.buckets[12].count, 2);
+    }
+
+    #[test]
+    fn pool_metrics_should_merge_workers() {
+        let metrics = PoolMetrics::new(2);
+        metrics.finished(0, "hello", Duration::from_micros(1900));
+        metrics.finished(1, "hello", Duration::from_millis(1));
+        metrics.finished(1, "bye", Duration::from_millis(7));
+
+        let stats = metrics.stats();
+        let hello = &stats.handlers["hello"];
+        assert_eq!(hello.count, 2);
+        // 1.9ms is above the 1ms bound
+        assert_eq!(hello.buckets[0].count, 1);
+        assert_eq!(hello.buckets[1].count, 2);
+        assert_eq!(stats.handlers["bye"].count, 1);
+        assert_eq!(stats.requests, 3);
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use axum::http::{header::COOKIE, HeaderMap};
+use serde::Serialize;
+
+use crate::{
+    middleware::{Credentials, RateLimits, ResponseCache},
+    AppError, AppRouter, AssetStore, PoolStats, ProjectConfig, Res, WorkerPool, DEFAULT_POOL,
+};
+
+/// header (or cookie) to force a request to a specific version. The value is
+/// a version id, `stable` or `canary`.
+pub const VERSION_HEADER: &str = "x-dino-version";
+const VERSION_COOKIE: &str = "dino-version";
+
+/// A tenant served by dino-server. The config, routes, code and worker pool
+/// of a tenant are swapped together, so a request never sees routes from one
+/// bundle and handlers from another.
+#[derive(Clone)]
+pub struct SwappableTenant {
+    /// name of the tenant in the admin api, the hosts it serves are the
+    /// `hosts` of its config
+    pub host: String,
+    pub pool_size: usize,
+    next_version: Arc<AtomicU64>,
+    /// kept across versions, a swap or a canary doesn't reset the limits
+    rate_limits: Arc<RateLimits>,
+    pub inner: Arc<ArcSwap<TenantInner>>,
+}
+
+pub struct TenantInner {
+    pub stable: Arc<TenantVersion>,
+    pub canary: Option<Canary>,
+    counter: AtomicU64,
+}
+
+/// A bundle version which receives `weight` percent of the traffic.
+pub struct Canary {
+    pub version: Arc<TenantVersion>,
+    pub weight: u8,
+}
+
+pub struct TenantVersion {
+    pub id: u64,
+    pub config: ProjectConfig,
+    pub code: String,
+    pub router: AppRouter,
+    pub assets: AssetStore,
+    pub credentials: Credentials,
+    /// responses of the routes with `cache`
+    pub cache: ResponseCache,
+    pub pool: WorkerPool,
+    pub pools: HashMap<String, WorkerPool>,
+    pub metrics: VersionMetrics,
+}
+
+#[derive(Debug, Default)]
+pub struct VersionMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct Tenant(Arc<TenantInner>);
+
+#[derive(Debug, Serialize)]
+pub struct TenantStatus {
+    pub host: String,
+    pub stable: VersionStatus,
+    pub canary: Option<VersionStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionStatus {
+    pub id: u64,
+    pub weight: u8,
+    pub requests: u64,
+    pub errors: u64,
+    pub pools: BTreeMap<String, PoolStats>,
+}
+
+impl SwappableTenant {
+    pub fn try_new(
+        host: impl Into<String>,
+        code: impl Into<String>,
+        config: ProjectConfig,
+        pool_size: usize,
+    ) -> anyhow::Result<Self> {
+        let rate_limits = Arc::new(RateLimits::default());
+        let stable = Arc::new(TenantVersion::try_new(
+            1,
+            code,
+            config,
+            pool_size,
+            &rate_limits,
+        )?);
+        Ok(Self {
+            host: host.into(),
+            pool_size,
+            next_version: Arc::new(AtomicU64::new(2)),
+            rate_limits,
+            inner: Arc::new(ArcSwap::from_pointee(TenantInner::new(stable, None))),
+        })
+    }
+
+    /// Replace all versions of the tenant with a new one.
+    pub fn swap(&self, code: impl Into<String>, config: ProjectConfig) -> anyhow::Result<()> {
+        let stable = Arc::new(self.new_version(code, config)?);
+        self.inner.store(Arc::new(TenantInner::new(stable, None)));
+        Ok(())
+    }
+
+    /// Deploy a new version next to the stable one, receiving `weight` percent
+    /// of the traffic. A previous canary is replaced.
+    pub fn deploy_canary(
+        &self,
+        code: impl Into<String>,
+        config: ProjectConfig,
+        weight: u8,
+    ) -> anyhow::Result<()> {
+        let version = Arc::new(self.new_version(code, config)?);
+        let stable = self.load().stable.clone();
+        let canary = Canary::new(version, weight);
+        self.inner
+            .store(Arc::new(TenantInner::new(stable, Some(canary))));
+        Ok(())
+    }
+
+    pub fn set_canary_weight(&self, weight: u8) -> Result<(), AppError> {
+        let tenant = self.load();
+        let canary = tenant.canary_or_err(&self.host)?;
+        let canary = Canary::new(canary.version.clone(), weight);
+        let inner = TenantInner::new(tenant.stable.clone(), Some(canary));
+        self.inner.store(Arc::new(inner));
+        Ok(())
+    }
+
+    /// Make the canary the stable version, the previous stable version is dropped.
+    pub fn promote(&self) -> Result<(), AppError> {
+        let tenant = self.load();
+        let canary = tenant.canary_or_err(&self.host)?;
+        let inner = TenantInner::new(canary.version.clone(), None);
+        self.inner.store(Arc::new(inner));
+        Ok(())
+    }
+
+    /// Drop the canary, all traffic goes back to the stable version.
+    pub fn abort(&self) -> Result<(), AppError> {
+        let tenant = self.load();
+        tenant.canary_or_err(&self.host)?;
+        let inner = TenantInner::new(tenant.stable.clone(), None);
+        self.inner.store(Arc::new(inner));
+        Ok(())
+    }
+
+    pub fn load(&self) -> Tenant {
+        Tenant(self.inner.load_full())
+    }
+
+    pub fn status(&self) -> TenantStatus {
+        let tenant = self.load();
+        let weight = tenant.canary.as_ref().map(|c| c.weight).unwrap_or(0);
+        TenantStatus {
+            host: self.host.clone(),
+            stable: tenant.stable.status(100 - weight),
+            canary: tenant.canary.as_ref().map(|c| c.version.status(c.weight)),
+        }
+    }
+
+    fn new_version(
+        &self,
+        code: impl Into<String>,
+        config: ProjectConfig,
+    ) -> anyhow::Result<TenantVersion> {
+        let id = self.next_version.fetch_add(1, Ordering::Relaxed);
+        TenantVersion::try_new(id, code, config, self.pool_size, &self.rate_limits)
+    }
+}
+
+impl Deref for Tenant {
+    type Target = TenantInner;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl TenantInner {
+    pub fn new(stable: Arc<TenantVersion>, canary: Option<Canary>) -> Self {
+        Self {
+            stable,
+            canary,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Pick the version serving a request: an explicit override by header or
+    /// cookie wins, otherwise the traffic is split by the canary weight.
+    pub fn select(&self, headers: &HeaderMap) -> Arc<TenantVersion> {
+        let Some(canary) = &self.canary else {
+            return self.stable.clone();
+        };
+
+        match version_override(headers).as_deref() {
+            Some("stable") => return self.stable.clone(),
+            Some("canary") => return canary.version.clone(),
+            Some(id) if id == canary.version.id.to_string() => return canary.version.clone(),
+            Some(id) if id == self.stable.id.to_string() => return self.stable.clone(),
+            _ => {}
+        }
+
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) % 100;
+        if n < canary.weight as u64 {
+            canary.version.clone()
+        } else {
+            self.stable.clone()
+        }
+    }
+
+    fn canary_or_err(&self, host: &str) -> Result<&Canary, AppError> {
+        self.canary
+            .as_ref()
+            .ok_or_else(|| AppError::CanaryNotFound(host.to_string()))
+    }
+}
+
+impl Canary {
+    pub fn new(version: Arc<TenantVersion>, weight: u8) -> Self {
+        Self {
+            version,
+            weight: weight.min(100),
+        }
+    }
+}
+
+impl TenantVersion {
+    pub fn try_new(
+        id: u64,
+        code: impl Into<String>,
+        config: ProjectConfig,
+        pool_size: usize,
+        rate_limits: &RateLimits,
+    ) -> anyhow::Result<Self> {
+        let code = code.into();
+        // check the config first, so an invalid config never spawns workers
+        let router = AppRouter::with_rate_limits(&config, rate_limits)?;
+        let assets = match &config.assets {
+            Some(dir) => AssetStore::load(dir)?,
+            None => AssetStore::default(),
+        };
+        anyhow::ensure!(
+            !config.pools.contains_key(DEFAULT_POOL),
+            "pool name `{DEFAULT_POOL}` is reserved"
+        );
+        let credentials = Credentials::load(&config)?;
+        for (path, routes) in config.flat_routes()? {
+            for name in routes.iter().filter_map(|r| r.pool.as_ref()) {
+                anyhow::ensure!(
+                    name == DEFAULT_POOL || config.pools.contains_key(name),
+                    "pool `{name}` used by {path} is not defined"
+                );
+            }
+            for auth in routes.iter().filter_map(|r| r.auth.as_ref()) {
+                credentials
+                    .check(auth)
+                    .with_context(|| format!("invalid auth of {path}"))?;
+            }
+        }
+
+        let pool = WorkerPool::try_new(&code, &config.pool, pool_size, &config)?;
+        let mut pools = HashMap::new();
+        for (name, pool_config) in &config.pools {
+            let pool = WorkerPool::try_new(&code, pool_config, pool_size, &config)?;
+            pools.insert(name.clone(), pool);
+        }
+        let cache = ResponseCache::new(config.cache_size);
+        Ok(Self {
+            id,
+            config,
+            code,
+            router,
+            assets,
+            credentials,
+            cache,
+            pool,
+            pools,
+            metrics: VersionMetrics::default(),
+        })
+    }
+
+    /// The worker pool with the given name, `None` for the default pool.
+    pub fn pool(&self, name: Option<&str>) -> &WorkerPool {
+        name.and_then(|name| self.pools.get(name))
+            .unwrap_or(&self.pool)
+    }
+
+    fn status(&self, weight: u8) -> VersionStatus {
+        VersionStatus {
+            id: self.id,
+            weight,
+            requests: self.metrics.requests.load(Ordering::Relaxed),
+            errors: self.metrics.errors.load(Ordering::Relaxed),
+            pools: self
+                .pools
+                .iter()
+                .map(|(name, pool)| (name.clone(), pool.stats()))
+                .chain([(DEFAULT_POOL.to_string(), self.pool.stats())])
+                .collect(),
+        }
+    }
+}
+
+impl VersionMetrics {
+    pub fn record(&self, res: &Result<Res, AppError>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !matches!(res, Ok(res) if res.status < 500) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn version_override(headers: &HeaderMap) -> Option<String> {
+    if let Some(v) = headers.get(VERSION_HEADER).and_then(|v| v.to_str().ok()) {
+        return Some(v.trim().to_string());
+    }
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|kv| kv.trim().split_once('='))
+        .find(|(k, _)| *k == VERSION_COOKIE)
+        .map(|(_, v)| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::http::{HeaderValue, Method};
+
+    use super::*;
+
+    const CODE: &str = r#"
+(function(){
+    async function hello(req){
+        return { status: 200, headers: {}, body: "hello" };
+    }
+    return{hello:hello};
+})();
+    "#;
+
+    fn config(content: &str) -> ProjectConfig {
+        serde_yaml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn tenant_swap_should_work() {
+        let config0 = config(include_str!("../fixtures/config.yml"));
+        let tenant = SwappableTenant::try_new("localhost", CODE, config0, 1).unwrap();
+        let old = tenant.load().stable.clone();
+        let m = old.router.match_it(Method::GET, "/api/hello/1").unwrap();
+        assert_eq!(m.value.handler, "hello");
+        assert_eq!(old.id, 1);
+
+        let config1 = config(include_str!("../fixtures/config1.yml"));
+        tenant.swap(CODE, config1).unwrap();
+        let new = tenant.load().stable.clone();
+        assert_eq!(new.id, 2);
+        let m = new.router.match_it(Method::GET, "/api/hello/1").unwrap();
+        assert_eq!(m.value.handler, "hello1");
+        let m = new.router.match_it(Method::POST, "/api/goodbye/2").unwrap();
+        assert_eq!(m.value.handler, "handler2");
+
+        // a snapshot taken before the swap keeps its own routes
+        let m = old.router.match_it(Method::GET, "/api/hello/1").unwrap();
+        assert_eq!(m.value.handler, "hello");
+    }
+
+    #[test]
+    fn tenant_canary_should_split_traffic() {
+        let config0 = config(include_str!("../fixtures/config.yml"));
+        let tenant = SwappableTenant::try_new("localhost", CODE, config0, 1).unwrap();
+        let config1 = config(include_str!("../fixtures/config1.yml"));
+        tenant.deploy_canary(CODE, config1, 5).unwrap();
+
+        let snapshot = tenant.load();
+        let headers = HeaderMap::new();
+        let canary = (0..100)
+            .filter(|_| snapshot.select(&headers).id == 2)
+            .count();
+        assert_eq!(canary, 5);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(VERSION_HEADER, HeaderValue::from_static("canary"));
+        assert_eq!(snapshot.select(&headers).id, 2);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("a=b; dino-version=1"));
+        tenant.set_canary_weight(100).unwrap();
+        assert_eq!(tenant.load().select(&headers).id, 1);
+        assert_eq!(tenant.load().select(&HeaderMap::new()).id, 2);
+    }
+
+    #[test]
+    fn tenant_promote_and_abort_should_work() {
+        let config0 = config(include_str!("../fixtures/config.yml"));
+        let tenant = SwappableTenant::try_new("localhost", CODE, config0, 1).unwrap();
+        assert!(tenant.promote().is_err());
+
+        let config1 = config(include_str!("../fixtures/config1.yml"));
+        tenant.deploy_canary(CODE, config1, 50).unwrap();
+        tenant.abort().unwrap();
+        assert_eq!(tenant.load().stable.id, 1);
+        assert!(tenant.load().canary.is_none());
+
+        let config1 = config(include_str!("../fixtures/config1.yml"));
+        tenant.deploy_canary(CODE, config1, 50).unwrap();
+        tenant.promote().unwrap();
+        let status = tenant.status();
+        assert_eq!(status.stable.id, 3);
+        assert_eq!(status.stable.weight, 100);
+        assert!(status.canary.is_none());
+    }
+
+    #[test]
+    fn tenant_should_use_route_pools() {
+        let config0 = config(include_str!("../fixtures/config2.yml"));
+        let tenant = SwappableTenant::try_new("localhost", CODE, config0, 2).unwrap();
+        let version = tenant.load().stable.clone();
+        let status = tenant.status();
+        assert_eq!(status.stable.pools["default"].size, 2);
+        assert_eq!(status.stable.pools["heavy"].size, 1);
+
+        let m = version.router.match_it(Method::GET, "/api/report").unwrap();
+        assert_eq!(m.value.pool.as_deref(), Some("heavy"));
+        let m = version.router.match_it(Method::GET, "/api/health").unwrap();
+        assert_eq!(m.value.pool, None);
+
+        let mut config1 = config(include_str!("../fixtures/config2.yml"));
+        config1.pools.clear();
+        assert!(tenant.swap(CODE, config1).is_err());
+    }
+
+    #[test]
+    fn tenant_versions_should_share_rate_limits() {
+        let yaml = r#"
+name: limited
+rate_limit:
+  requests: 2
+  period: 60
+routes:
+  /api/hello:
+    - method: GET
+      handler: hello
+      rate_limit:
+        requests: 1
+        period: 60
+"#;
+        let tenant = SwappableTenant::try_new("localhost", CODE, config(yaml), 1).unwrap();
+        let check = |version: &TenantVersion| {
+            let now = Instant::now();
+            version
+                .router
+                .rate_limits(&Method::GET, "/api/hello")
+                .map(|limiter| limiter.check("ip:127.0.0.1", now).allowed)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(check(&tenant.load().stable), [true, true]);
+
+        // the canary takes from the same buckets
+        tenant.deploy_canary(CODE, config(yaml), 50).unwrap();
+        let canary = tenant.load().canary.as_ref().unwrap().version.clone();
+        assert_eq!(check(&canary), [false, true]);
+
+        // and the limits outlive a swap
+        tenant.swap(CODE, config(yaml)).unwrap();
+        assert_eq!(check(&tenant.load().stable), [false, false]);
+    }
+}
@@ -1,64 +1,63 @@
-use std::{ops::Deref, sync::Arc};
+use std::time::{Duration, Instant};
 
-use arc_swap::ArcSwap;
+use crate::{
+    AppError, JsWorkerPool, PoolConfig, PoolStats, ProjectConfig, Req, Res, WorkerOptions,
+};
 
-use crate::{JsWorkerPool, Req, Res};
+/// name of the pool used by routes without a `pool`
+pub const DEFAULT_POOL: &str = "default";
 
-#[derive(Clone)]
-pub struct SwappableWorkerPool {
-    pub size: usize,
-    pub inner: Arc<ArcSwap<WorkerPoolInner>>,
+pub struct WorkerPool {
+    pool: JsWorkerPool,
+    timeout: Option<Duration>,
 }
 
-pub struct WorkerPoolInner {
-    pub code: String,
-    pub pool: JsWorkerPool,
-}
-
-#[derive(Clone)]
-pub struct WorkerPool(Arc<WorkerPoolInner>);
-
-impl SwappableWorkerPool {
-    pub fn try_new(code: impl Into<String>, size: usize) -> anyhow::Result<Self> {
-        let code = code.into();
-        let pool = JsWorkerPool::new(size, &code);
-        let inner = WorkerPoolInner::new(code, pool);
+impl WorkerPool {
+    pub fn try_new(
+        code: &str,
+        config: &PoolConfig,
+        default_size: usize,
+        project: &ProjectConfig,
+    ) -> anyhow::Result<Self> {
+        let size = config.size.unwrap_or(default_size);
+        anyhow::ensure!(size > 0, "pool size must be greater than 0");
+        let options = WorkerOptions {
+            recycle: config.recycle.clone(),
+            isolation: project.isolation,
+            env: project.env.clone().into_iter().collect(),
+        };
+        let pool = JsWorkerPool::try_new(size, code, options)?;
         Ok(Self {
-            size,
-            inner: Arc::new(ArcSwap::from_pointee(inner)),
+            pool,
+            timeout: config.timeout.map(Duration::from_millis),
         })
     }
 
-    pub fn swap(&self, code: impl Into<String>) -> anyhow::Result<()> {
-        let code = code.into();
-        let pool = JsWorkerPool::new(self.size, &code);
-        let inner = WorkerPoolInner::new(code, pool);
-        self.inner.store(Arc::new(inner));
-        Ok(())
-    }
-
-    pub fn load(&self) -> WorkerPool {
-        WorkerPool(self.inner.load_full())
-    }
-}
-
-impl Deref for WorkerPool {
-    type Target = WorkerPoolInner;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl WorkerPoolInner {
-    pub fn new(code: impl Into<String>, pool: JsWorkerPool) -> Self {
-        Self {
-            code: code.into(),
-            pool,
-        }
+    /// Run the middleware and the handler of a route, `timeout` overrides
+    /// the timeout of the pool. A handler still running at the timeout is
+    /// interrupted, so it doesn't keep its worker busy.
+    pub async fn run(
+        &self,
+        name: &str,
+        middleware: &[String],
+        req: Req,
+        timeout: Option<Duration>,
+    ) -> Result<Res, AppError> {
+        let timeout = timeout.or(self.timeout);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let rx = self.pool.run(name, middleware, req, deadline).await?;
+        let res = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, rx)
+                .await
+                .map_err(|_| AppError::WorkerTimeout(name.to_string()))?,
+            None => rx.await,
+        };
+        // a js exception is a 500, like any other failure of the handler
+        Ok(res.map_err(anyhow::Error::from)??)
     }
 
-    pub async fn run(&self, name: &str, req: Req) -> anyhow::Result<Res> {
-        let rx = self.pool.run(name, req).await;
-        Ok(rx.recv()?)
+    /// Queue depth, busy workers and handler latencies of the pool.
+    pub fn stats(&self) -> PoolStats {
+        self.pool.stats()
     }
 }
@@ -5,16 +5,23 @@ use clap::Parser;
 use crate::{build_project, CmdExecutor};
 
 #[derive(Debug, Parser)]
-pub struct BuildOpts {}
+pub struct BuildOpts {
+    /// environment whose config overlay, e.g. `config.prod.yml`, is merged
+    /// into config.yml
+    #[arg(short, long)]
+    pub env: Option<String>,
+}
 
 impl CmdExecutor for BuildOpts {
     async fn execute(self) -> anyhow::Result<()> {
         let cur_dir = env::current_dir()?.display().to_string();
-        let (filename, cached) = build_project(&cur_dir)?;
+        let env = self.env.as_deref();
+        let (filename, cached) = build_project(&cur_dir, env)?;
+        let env = env.map(|env| format!(" [{env}]")).unwrap_or_default();
         if cached {
-            eprintln!("Build success: {} (cached)", filename);
+            eprintln!("Build success{env}: {} (cached)", filename);
         } else {
-            eprintln!("Build success: {}", filename);
+            eprintln!("Build success{env}: {}", filename);
         }
         Ok(())
     }
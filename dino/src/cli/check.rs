@@ -0,0 +1,18 @@
+use bundler::run_bundle;
+use clap::Parser;
+use dino_server::ProjectConfig;
+
+use crate::{check_handlers, CmdExecutor};
+
+#[derive(Debug, Parser)]
+pub struct CheckOpts {}
+
+impl CmdExecutor for CheckOpts {
+    async fn execute(self) -> anyhow::Result<()> {
+        let config = ProjectConfig::load("config.yml")?;
+        let code = run_bundle("main.ts", &Default::default())?;
+        check_handlers(&code, &config)?;
+        eprintln!("Check success: {}", config.name);
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use clap::{Parser, Subcommand};
+use dino_server::ProjectConfig;
+
+use crate::CmdExecutor;
+
+#[derive(Debug, Parser)]
+pub struct ConfigOpts {
+    #[command(subcommand)]
+    pub cmd: ConfigSubCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigSubCommand {
+    #[command(name = "schema", about = "Print the JSON Schema of config.yml")]
+    Schema,
+    #[command(
+        name = "show",
+        about = "Print config.yml with includes, overlay and environment variables resolved"
+    )]
+    Show {
+        /// environment whose config overlay, e.g. `config.prod.yml`, is
+        /// merged into config.yml
+        #[arg(short, long)]
+        env: Option<String>,
+    },
+}
+
+impl CmdExecutor for ConfigOpts {
+    async fn execute(self) -> anyhow::Result<()> {
+        match self.cmd {
+            ConfigSubCommand::Schema => {
+                let schema = ProjectConfig::json_schema();
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+            }
+            ConfigSubCommand::Show { env } => {
+                // checked first, so what is printed is what the server loads
+                ProjectConfig::load_env("config.yml", env.as_deref())?;
+                let value = ProjectConfig::resolve_yaml("config.yml", env.as_deref())?;
+                print!("{}", serde_yaml::to_string(&value)?);
+            }
+        }
+        Ok(())
+    }
+}
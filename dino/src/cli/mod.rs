@@ -1,11 +1,15 @@
 mod build;
+mod check;
+mod config;
 mod init;
 mod run;
 
 use clap::Parser;
 use enum_dispatch::enum_dispatch;
 
-pub use self::{build::BuildOpts, init::InitOpts, run::RunOpts};
+pub use self::{
+    build::BuildOpts, check::CheckOpts, config::ConfigOpts, init::InitOpts, run::RunOpts,
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "dino", version, author, about, long_about = None)]
@@ -21,6 +25,10 @@ pub enum SubCommand {
     Init(InitOpts),
     #[command(name = "build", about = "Build dino project")]
     Build(BuildOpts),
+    #[command(name = "check", about = "Check dino project handlers")]
+    Check(CheckOpts),
+    #[command(name = "config", about = "Inspect dino project config")]
+    Config(ConfigOpts),
     #[command(name = "run", about = "Run dino project")]
     Run(RunOpts),
 }
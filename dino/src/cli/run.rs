@@ -1,9 +1,8 @@
-use std::{fs, time::Duration};
+use std::{fs, net::IpAddr, time::Duration};
 
 use clap::Parser;
 use dino_server::{
-    start_server, ProjectConfig, SwappableAppRouter, SwappableWorkerPool, TenentRouter,
-    TenentWorkerPool,
+    start_admin_server, start_server, ProjectConfig, ServerOptions, SwappableTenant, TenantMode,
 };
 use notify::RecursiveMode;
 use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
@@ -12,34 +11,66 @@ use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
-use crate::{build_project, CmdExecutor};
+use crate::{build_project, CmdExecutor, BUILD_DIR};
 
 const MONITOR_FS_INTERVAL: Duration = Duration::from_secs(2);
 const WOERK_POOL_SIZE: usize = 10;
+const DEFAULT_PORT: u16 = 3000;
 
 #[derive(Debug, Parser)]
 pub struct RunOpts {
-    #[arg(short, long, default_value_t = 3000)]
-    pub port: u16,
+    /// port to listen on, defaults to `port` of the config or 3000
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// port of the admin server, listening on localhost only
+    #[arg(long, default_value_t = 3001)]
+    pub admin_port: u16,
+    /// deploy changes as a canary receiving this percent of the traffic,
+    /// instead of replacing the running version
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub canary: Option<u8>,
+    /// environment whose config overlay, e.g. `config.prod.yml`, is merged
+    /// into config.yml
+    #[arg(short, long)]
+    pub env: Option<String>,
+    /// proxy allowed to set the request host with `X-Forwarded-Host`, can
+    /// be given more than once
+    #[arg(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// serve the tenant at `/t/<tenant>/` instead of on its hosts
+    #[arg(long)]
+    pub path_prefix: bool,
 }
 
 impl CmdExecutor for RunOpts {
     async fn execute(self) -> anyhow::Result<()> {
         let layer = Layer::new().with_filter(LevelFilter::INFO);
         tracing_subscriber::registry().with(layer).init();
-        let (code, config) = get_code_and_config()?;
-        let router = SwappableAppRouter::try_new(&code, config.routes)?;
-        let pool = SwappableWorkerPool::try_new(code, WOERK_POOL_SIZE)?;
-        let routers = vec![TenentRouter::new("localhost", router.clone())];
-        let pools = vec![TenentWorkerPool::new("localhost", pool.clone())];
-        tokio::spawn(async_watch(".", router, pool));
-        start_server(self.port, routers, pools).await?;
+        let (code, config) = get_code_and_config(self.env.as_deref())?;
+        let port = self.port.or(config.port).unwrap_or(DEFAULT_PORT);
+        // by host, the tenant is served on the `hosts` of the config and the
+        // admin server knows it by the first one. By path, it is mounted at
+        // `/t/<name>`
+        let (tenant_mode, name) = if self.path_prefix {
+            (TenantMode::PathPrefix, config.name.clone())
+        } else {
+            let name = config.hosts.first().unwrap_or(&config.name).clone();
+            (TenantMode::Host, name)
+        };
+        let tenant = SwappableTenant::try_new(name, code, config, WOERK_POOL_SIZE)?;
+        tokio::spawn(async_watch(".", tenant.clone(), self.canary, self.env));
+        tokio::spawn(start_admin_server(self.admin_port, vec![tenant.clone()]));
+        let options = ServerOptions {
+            trusted_proxies: self.trusted_proxies,
+            tenant_mode,
+        };
+        start_server(port, vec![tenant], options).await?;
         Ok(())
     }
 }
 
-fn get_code_and_config() -> anyhow::Result<(String, ProjectConfig)> {
-    let (filename, _) = build_project(".")?;
+fn get_code_and_config(env: Option<&str>) -> anyhow::Result<(String, ProjectConfig)> {
+    let (filename, _) = build_project(".", env)?;
     let config = filename.replace(".mjs", ".yml");
     let code = fs::read_to_string(filename)?;
     let config = ProjectConfig::load(config)?;
@@ -48,8 +79,9 @@ fn get_code_and_config() -> anyhow::Result<(String, ProjectConfig)> {
 
 async fn async_watch(
     p: &str,
-    router: SwappableAppRouter,
-    pool: SwappableWorkerPool,
+    tenant: SwappableTenant,
+    canary: Option<u8>,
+    env: Option<String>,
 ) -> anyhow::Result<()> {
     let (tx, rx) = channel(1);
 
@@ -68,19 +100,22 @@ async fn async_watch(
                 let mut need_swap = false;
                 for event in events {
                     let path = event.path;
+                    if path.components().any(|c| c.as_os_str() == BUILD_DIR) {
+                        continue;
+                    }
                     let ext = path.extension().unwrap_or_default();
-                    if path.ends_with("config.yml") || ext == "ts" || ext == "js" {
+                    // config.yml may include other yaml files
+                    if ["ts", "js", "yml", "yaml"].iter().any(|e| ext == *e) {
                         info!("File changed: {}", path.display());
                         need_swap = true;
                         break;
                     }
                 }
                 if need_swap {
-                    let (code, config) = get_code_and_config()?;
-                    router.swap(code.clone(), config.routes)?;
-                    info!("Router swapped");
-                    pool.swap(code)?;
-                    info!("Worker Pool swapped");
+                    // e.g. a failing `init` hook, the running version keeps serving
+                    if let Err(e) = reload(&tenant, canary, env.as_deref()) {
+                        warn!("Reload failed: {:?}", e);
+                    }
                 }
             }
             Err(e) => {
@@ -91,3 +126,18 @@ async fn async_watch(
 
     Ok(())
 }
+
+fn reload(tenant: &SwappableTenant, canary: Option<u8>, env: Option<&str>) -> anyhow::Result<()> {
+    let (code, config) = get_code_and_config(env)?;
+    match canary {
+        Some(weight) => {
+            tenant.deploy_canary(code, config, weight)?;
+            info!("Canary deployed with weight {weight}");
+        }
+        None => {
+            tenant.swap(code, config)?;
+            info!("Tenant swapped");
+        }
+    }
+    Ok(())
+}
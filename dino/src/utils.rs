@@ -1,12 +1,15 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fs::{self, File},
-    io,
     path::{Path, PathBuf},
 };
 
+use anyhow::bail;
 use bundler::run_bundle;
+use dino_server::{asset_manifest_path, AssetEntry, AssetManifest, ProjectConfig};
 use glob::{glob, GlobError};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
 use crate::BUILD_DIR;
 
@@ -26,6 +29,43 @@ pub(crate) fn calc_project_hash(dir: &str) -> anyhow::Result<String> {
     calc_hash_for_files(dir, &["ts", "fs", "json", "yml", "yaml"], 16)
 }
 
+// all files in a directory, by their path relative to it with `/` separators
+pub(crate) fn get_asset_files(dir: &str) -> anyhow::Result<BTreeMap<String, PathBuf>> {
+    let mut files = BTreeMap::new();
+    for path in glob(&format!("{}/**/*", dir.trim_end_matches('/')))? {
+        let path = path?;
+        if !path.is_file() {
+            continue;
+        }
+        let name = path
+            .strip_prefix(dir)?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.insert(name, path);
+    }
+    Ok(files)
+}
+
+// content hashes of the asset files
+pub(crate) fn calc_asset_manifest(
+    files: &BTreeMap<String, PathBuf>,
+) -> anyhow::Result<AssetManifest> {
+    let mut manifest = AssetManifest::default();
+    for (name, path) in files {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(File::open(path)?)?;
+        let mut hash = hasher.finalize().to_string();
+        hash.truncate(16);
+        let size = fs::metadata(path)?.len();
+        manifest
+            .files
+            .insert(name.clone(), AssetEntry { hash, size });
+    }
+    Ok(manifest)
+}
+
 pub(crate) fn calc_hash_for_files(dir: &str, exts: &[&str], len: usize) -> anyhow::Result<String> {
     let files = get_files_with_exts(dir, exts)?;
     let mut hasher = blake3::Hasher::new();
@@ -39,8 +79,38 @@ pub(crate) fn calc_hash_for_files(dir: &str, exts: &[&str], len: usize) -> anyho
 
 // calculate the project hash, if the hash is different,
 // rebuild the project, otherwise return the project path.
-pub(crate) fn build_project(dir: &str) -> anyhow::Result<(String, bool)> {
-    let hash = calc_project_hash(dir)?;
+// `env` selects the config overlay, e.g. `config.prod.yml` for `prod`.
+pub(crate) fn build_project(dir: &str, env: Option<&str>) -> anyhow::Result<(String, bool)> {
+    // checked first, errors point into config.yml and its includes
+    let checked = ProjectConfig::load_env("config.yml", env)?;
+    // included files and the overlay are merged, so the build has a single
+    // config
+    let mut value = ProjectConfig::load_yaml("config.yml", env)?;
+    let assets = match value.get("assets").and_then(Value::as_str) {
+        Some(assets) => {
+            let files = get_asset_files(assets)?;
+            let manifest = calc_asset_manifest(&files)?;
+            Some((files, manifest))
+        }
+        None => None,
+    };
+
+    let mut hash = calc_project_hash(dir)?;
+    if assets.is_some() || env.is_some() {
+        // builds of the same files for different environments differ, and
+        // assets may have any extension, so they are hashed separately
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(hash.as_bytes());
+        if let Some(env) = env {
+            hasher.update(format!("env:{env}").as_bytes());
+        }
+        if let Some((_, manifest)) = &assets {
+            hasher.update(&serde_json::to_vec(manifest)?);
+        }
+        hash = hasher.finalize().to_string();
+        hash.truncate(16);
+    }
+
     fs::create_dir_all(BUILD_DIR)?;
     let filename = format!("{}/{}.mjs", BUILD_DIR, hash);
     let config = format!("{}/{}.yml", BUILD_DIR, hash);
@@ -51,14 +121,63 @@ pub(crate) fn build_project(dir: &str) -> anyhow::Result<(String, bool)> {
 
     // bundle the project
     let content = run_bundle("main.ts", &Default::default())?;
+    // checked before writing anything, a failed build is never cached
+    check_handlers(&content, &checked)?;
+
+    if let Some((files, manifest)) = assets {
+        let assets_dir = format!("{}/{}-assets", BUILD_DIR, hash);
+        for (name, src) in &files {
+            let dst = Path::new(&assets_dir).join(name);
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(src, dst)?;
+        }
+        fs::create_dir_all(&assets_dir)?;
+        let manifest = serde_json::to_string_pretty(&manifest)?;
+        // next to the assets, a `manifest.json` of the project is an asset
+        fs::write(asset_manifest_path(&assets_dir), manifest)?;
+        // the server loads the assets of the build, not the project's
+        value["assets"] = Value::String(assets_dir);
+    }
+    fs::write(config, serde_yaml::to_string(&value)?)?;
+    let info = BuildInfo {
+        hash: hash.clone(),
+        env: env.map(String::from),
+    };
+    let info_file = format!("{}/{}.json", BUILD_DIR, hash);
+    fs::write(info_file, serde_json::to_string_pretty(&info)?)?;
+    // written last, it marks the build as complete
     fs::write(dst, content)?;
-    let mut dst = File::create(config)?;
-    let mut src = File::open("config.yml")?;
-    io::copy(&mut src, &mut dst)?;
 
     Ok((filename, false))
 }
 
+/// Written next to the artifacts of a build as `<hash>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub hash: String,
+    /// environment whose config overlay was merged, `None` for the base
+    /// config only
+    pub env: Option<String>,
+}
+
+// check the handlers of the config against the exports of the bundle,
+// missing handlers fail the build, unused ones are reported as warnings.
+pub(crate) fn check_handlers(code: &str, config: &ProjectConfig) -> anyhow::Result<()> {
+    let check = config.check_handlers(code)?;
+    for name in &check.unused {
+        eprintln!("warning: `{name}` is exported by main.ts but not used by any route");
+    }
+    if !check.missing.is_empty() {
+        bail!(
+            "handlers not exported by main.ts: {}",
+            check.missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,6 +197,18 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn calc_asset_manifest_should_work() -> anyhow::Result<()> {
+        let files = get_asset_files("fixtures/prj/test1")?;
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["b.ts", "c.js"]);
+        let manifest = calc_asset_manifest(&files)?;
+        for (name, entry) in &manifest.files {
+            assert_eq!(entry.hash.len(), 16);
+            assert_eq!(entry.size, fs::metadata(&files[name])?.len());
+        }
+        Ok(())
+    }
+
     #[test]
     fn calc_hash_for_filesshould_work() -> anyhow::Result<()> {
         let hash = calc_hash_for_files("fixtures/prj", &["ts", "js", "json"], 12)?;